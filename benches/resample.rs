@@ -0,0 +1,36 @@
+// Benchmarks for resampling, the part of stretching audio that runs in parallel. Decoding and encoding are left out,
+// since they're done by minimp3 and LAME. Run these with `cargo bench`.
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main, Throughput};
+use osurate::audio;
+
+const SAMPLE_RATE: usize = 44_100;
+
+// Returns `seconds` seconds of an interleaved stereo 440 Hz sine wave.
+fn sine_wave(seconds: usize) -> Vec<i16> {
+    let frames = (0..SAMPLE_RATE * seconds).map(|i| {
+        let t = i as f64 / SAMPLE_RATE as f64;
+        ((t * 440. * std::f64::consts::TAU).sin() * 8_000.) as i16
+    });
+    frames.flat_map(|s| [s, s]).collect()
+}
+
+fn bench_resample(c: &mut Criterion) {
+    let samples = sine_wave(10);
+    let mut group = c.benchmark_group("resample_parallel");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements((samples.len() / 2) as u64));
+
+    for &rate in &[0.75, 1.5] {
+        for &n_threads in &[1, 2, 4, 8] {
+            let id = BenchmarkId::new(format!("{}x", rate), format!("{} threads", n_threads));
+            group.bench_with_input(id, &n_threads, |b, &n_threads| {
+                b.iter(|| audio::resample_parallel(samples.clone(), 2, rate, n_threads))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_resample);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use osurate::beatmap::{Beatmap, ChangeRateError, RateOptions};
+
+// Arbitrary input should only ever be rejected with a `ParseError`, and anything that parses should either be able to
+// have its rate changed and be written back out, or be too long for the changed times to fit in osu!'s 32-bit integers.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut map) = Beatmap::parse(data) {
+        match map.change_rate(1.3, &RateOptions::default()) {
+            Ok(()) => {
+                map.into_string();
+            }
+            Err(e) => assert!(matches!(e, ChangeRateError::TimeOutOfRange), "unexpected error: {}", e),
+        }
+    }
+});
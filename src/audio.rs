@@ -1,12 +1,25 @@
-use std::{result, thread};
+use std::{error, fmt, result, thread};
+use std::f64::consts::PI;
+use std::fs;
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "ffmpeg")]
+use std::io;
+#[cfg(feature = "ffmpeg")]
+use std::process::{Command, Stdio};
+#[cfg(feature = "ffmpeg")]
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use dasp::{signal, Signal};
 use dasp::interpolate::linear::Linear;
+#[cfg(all(feature = "lame", not(feature = "bundled-lame")))]
 use lame::Lame;
-use minimp3::Decoder;
+#[cfg(feature = "bundled-lame")]
+use mp3lame_encoder::{Bitrate, Builder, DualPcm, FlushNoGap, Quality};
+use minimp3::{Decoder, Frame};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
 
 use crate::beatmap::Beatmap;
 use crate::util;
@@ -16,52 +29,261 @@ pub enum AudioStretchError {
     SourceNotFound,
     InvalidSource,
     UnsupportedChannelCount,
+    InvalidRate,
+    InvalidPitch,
+    InvalidLoudnessTarget,
+    FfmpegError,
+    UnsupportedByFfmpeg,
     LameInitializationError,
+    LameConfigurationError,
     LameEncodingError,
+    DestinationIsSource,
     DestinationIoError,
 }
 
+impl fmt::Display for AudioStretchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AudioStretchError::SourceNotFound => "couldn't find mp3 file",
+            AudioStretchError::InvalidSource => "couldn't parse mp3 file",
+            AudioStretchError::UnsupportedChannelCount => "unsupported mp3 channel count",
+            AudioStretchError::InvalidRate => "rate must be positive",
+            AudioStretchError::InvalidPitch => "pitch must be a finite number of semitones",
+            AudioStretchError::InvalidLoudnessTarget => "loudness target must be negative",
+            AudioStretchError::FfmpegError => "ffmpeg couldn't stretch the audio",
+            AudioStretchError::UnsupportedByFfmpeg => "loudness matching isn't supported with ffmpeg",
+            AudioStretchError::LameInitializationError => {
+                return write!(f, "couldn't initialize lame; {}", lame_install_hint());
+            }
+            AudioStretchError::LameConfigurationError => "lame rejected the mp3 encoder settings",
+            AudioStretchError::LameEncodingError => "lame mp3 encoding error",
+            AudioStretchError::DestinationIsSource => "stretched mp3 file would overwrite the original",
+            AudioStretchError::DestinationIoError => "mp3 output i/o error",
+        })
+    }
+}
+
+impl error::Error for AudioStretchError {}
+
+// Since LAME is dynamically linked, osurate won't start at all if it is missing entirely. An initialization failure
+// usually means that an incompatible or broken version of the library was found instead.
+fn lame_install_hint() -> &'static str {
+    if cfg!(target_os = "windows") {
+        "make sure a working libmp3lame.dll is next to the osurate executable"
+    } else if cfg!(target_os = "macos") {
+        "try reinstalling it with `brew reinstall lame`"
+    } else {
+        "try reinstalling libmp3lame with your package manager (i.e. `apt install --reinstall libmp3lame0`)"
+    }
+}
+
+#[cfg(not(any(feature = "lame", feature = "bundled-lame")))]
+compile_error!("an mp3 encoder is required; enable either the `lame` or the `bundled-lame` feature");
+
+#[cfg(all(feature = "lame", not(feature = "bundled-lame")))]
 impl From<lame::Error> for AudioStretchError {
     fn from(_: lame::Error) -> Self {
-        Self::LameInitializationError
+        Self::LameConfigurationError
     }
 }
 
-type Result<T> = result::Result<T, AudioStretchError>;
+pub type Result<T> = result::Result<T, AudioStretchError>;
+
+// A way of stretching audio read from `src` by a factor of `rate`, writing the output to `dest`. This allows for other
+// backends (i.e. ones supporting more formats) to be used in place of `Mp3Stretcher`.
+pub trait AudioStretcher {
+    fn stretch(&self, src: &mut dyn Read, dest: &mut dyn Write, rate: f64) -> Result<()>;
+}
 
-// Stretches the audio associated with the given `map` by a factor of `rate`, updating metadata.
-pub fn stretch_beatmap_audio(map: &mut Beatmap, dir: &Path, rate: f64) -> Result<()> {
-    let old_path = dir.join(&map.general_info.audio_file);
-    let old_audio = File::open(&old_path).or(Err(AudioStretchError::SourceNotFound))?;
+// The default backend, which decodes MP3 audio with minimp3, resamples it with dasp, and encodes it again with LAME.
+// Resampling is done with `n_threads` threads, or one per core if `None`, the pitch is shifted by `semitones` on top
+// of the change caused by the rate, the loudness is matched to `loudness_target` if set, and audio that is sped up is
+// low-pass filtered first if `anti_alias` is set (see `stretch`).
+#[derive(Clone, Debug, Default)]
+pub struct Mp3Stretcher {
+    pub n_threads: Option<usize>,
+    pub semitones: f64,
+    pub loudness_target: Option<f64>,
+    pub anti_alias: bool,
+}
 
-    // This looks like "audio.mp3" -> "audio_1_2.mp3" for a rate of 1.2.
-    let new_path = dir.join(format!(
-        "{}_{}.{}",
-        old_path.file_stem().ok_or(AudioStretchError::InvalidSource)?.to_string_lossy(),
-        rate.to_string().replace('.', "_"),
-        old_path.extension().ok_or(AudioStretchError::InvalidSource)?.to_string_lossy(),
-    ));
-    let mut new_audio = File::create(&new_path).or(Err(AudioStretchError::DestinationIoError))?;
-    stretch(old_audio, &mut new_audio, rate)?;
+impl AudioStretcher for Mp3Stretcher {
+    fn stretch(&self, src: &mut dyn Read, dest: &mut dyn Write, rate: f64) -> Result<()> {
+        stretch(src, dest, rate, self.semitones, self.loudness_target, self.anti_alias, self.n_threads)
+    }
+}
 
-    // This should be fine, since the file name was created just above.
-    map.general_info.audio_file = new_path.file_name().unwrap().to_str().unwrap().to_string();
-    Ok(())
+// A backend which runs ffmpeg (`executable`, or the one found in PATH if `None`) to decode, stretch, and encode audio,
+// so it can read any format ffmpeg can. Like `Mp3Stretcher`, the pitch changes along with the rate, and is shifted by
+// another `fallback.semitones`; the output is always 44.1 kHz MP3 audio. If ffmpeg can't be found, `fallback` is used
+// instead. Loudness matching isn't supported.
+#[cfg(feature = "ffmpeg")]
+#[derive(Clone, Debug, Default)]
+pub struct FfmpegStretcher {
+    pub executable: Option<PathBuf>,
+    pub fallback: Mp3Stretcher,
 }
 
-// Stretches MP3 audio read from `src` by a factor of `rate`, writing the output to `dest` as MP3 audio.
-fn stretch(src: impl Read, dest: &mut impl Write, rate: f64) -> Result<()> {
-    // Decode source MP3 data into i16 PCM data.
-    let mut decoder = Decoder::new(src);
-    let mut frames = vec![];
-    while let Ok(frame) = decoder.next_frame() {
-        frames.push(frame);
+#[cfg(feature = "ffmpeg")]
+impl AudioStretcher for FfmpegStretcher {
+    fn stretch(&self, src: &mut dyn Read, dest: &mut dyn Write, rate: f64) -> Result<()> {
+        util::verify(rate > 0. && rate.is_finite(), AudioStretchError::InvalidRate)?;
+        util::verify(self.fallback.semitones.is_finite(), AudioStretchError::InvalidPitch)?;
+        util::verify(self.fallback.loudness_target.is_none(), AudioStretchError::UnsupportedByFfmpeg)?;
+        let mut data = vec![];
+        src.read_to_end(&mut data).or(Err(AudioStretchError::InvalidSource))?;
+
+        let executable = self.executable.as_deref().unwrap_or_else(|| Path::new("ffmpeg"));
+        let mut ffmpeg = match Command::new(executable)
+            .args(["-hide_banner", "-loglevel", "error", "-i", "pipe:0", "-vn", "-map_metadata", "-1", "-af"])
+            .arg(ffmpeg_filters(rate, self.fallback.semitones))
+            .args(["-c:a", "libmp3lame", "-b:a", "128k", "-write_xing", "0", "-f", "mp3", "pipe:1"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(ffmpeg) => ffmpeg,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if !FFMPEG_NOT_FOUND_WARNED.swap(true, Ordering::Relaxed) {
+                    util::log_warn("couldn't find ffmpeg; stretching audio with the native backend instead");
+                }
+                return self.fallback.stretch(&mut data.as_slice(), dest, rate);
+            }
+            Err(_) => return Err(AudioStretchError::FfmpegError),
+        };
+
+        // The input is written on another thread, since ffmpeg may block on writing its output before it has read all
+        // of its input.
+        let mut stdin = ffmpeg.stdin.take().unwrap();
+        let writer = thread::spawn(move || stdin.write_all(&data));
+        let output = ffmpeg.wait_with_output().or(Err(AudioStretchError::FfmpegError))?;
+        let written = matches!(writer.join(), Ok(Ok(())));
+        util::verify(written && output.status.success(), AudioStretchError::FfmpegError)?;
+        dest.write_all(&output.stdout).or(Err(AudioStretchError::DestinationIoError))
     }
-    match decoder.next_frame() {
-        Err(minimp3::Error::Eof) | Err(minimp3::Error::SkippedData) => {}
-        _ => return Err(AudioStretchError::InvalidSource),
+}
+
+// Whether the ffmpeg backend has already warned about falling back to the native one, so that it only does so once.
+#[cfg(feature = "ffmpeg")]
+static FFMPEG_NOT_FOUND_WARNED: AtomicBool = AtomicBool::new(false);
+
+// Returns the ffmpeg filters that stretch audio like `stretch` does. The audio is played back faster by changing its
+// sample rate (which changes the pitch too), then resampled back to a normal rate. The extra pitch shift is undone in
+// the tempo with `atempo`, which only accepts factors from 0.5 to 2 in older versions of ffmpeg, so it may be repeated.
+#[cfg(feature = "ffmpeg")]
+fn ffmpeg_filters(rate: f64, semitones: f64) -> String {
+    let pitch = 2f64.powf(semitones / 12.);
+    let mut filters = format!("aresample=44100,asetrate={},aresample=44100", (44_100. * rate * pitch).round());
+    let mut tempo = 1. / pitch;
+    while (tempo - 1.).abs() > 1e-9 {
+        let step = tempo.clamp(0.5, 2.);
+        filters += &format!(",atempo={}", step);
+        tempo /= step;
     }
+    filters
+}
+
+// Stretches the audio associated with the given `map` (in `src_dir`) by a factor of `rate`, updating metadata, and
+// returns the path of the new audio file in `dest_dir`. At a rate of 1.0, the original audio is reused as-is, since
+// re-encoding it would only degrade its quality, and no file is created unless `dest_dir` is a different directory.
+// The audio is stretched with `stretcher`.
+pub fn stretch_beatmap_audio(
+    map: &mut Beatmap,
+    src_dir: &Path,
+    dest_dir: &Path,
+    rate: f64,
+    stretcher: &dyn AudioStretcher,
+) -> Result<Option<PathBuf>> {
+    let old_path = audio_source_path(src_dir, &map.general_info.audio_file);
+    let new_path = match stretched_audio_path(map, src_dir, dest_dir, rate)? {
+        Some(new_path) => new_path,
+        _ => return Ok(None),
+    };
+
+    // The original audio has to be copied over for it to be found next to a map saved elsewhere.
+    if rate == 1. {
+        util::verify(old_path.is_file(), AudioStretchError::SourceNotFound)?;
+        util::retry_io(&new_path, || fs::copy(&old_path, &new_path)).or(Err(AudioStretchError::DestinationIoError))?;
+    } else {
+        stretch_file(&old_path, &new_path, rate, stretcher)?;
+    }
+
+    // This should be fine, since the file name was created just above.
+    map.general_info.audio_file = new_path.file_name().unwrap().to_string_lossy().to_string();
+    Ok(Some(new_path))
+}
+
+// Returns the path in `dest_dir` that `stretch_beatmap_audio` would save the audio of `map` (in `src_dir`) to at a
+// rate of `rate`, or `None` if it wouldn't create a file.
+pub fn stretched_audio_path(map: &Beatmap, src_dir: &Path, dest_dir: &Path, rate: f64) -> Result<Option<PathBuf>> {
+    let old_path = audio_source_path(src_dir, &map.general_info.audio_file);
+    let new_name = if rate == 1. {
+        if util::is_same_file(src_dir, dest_dir) {
+            return Ok(None);
+        }
+        old_path.file_name().map(PathBuf::from)
+    } else {
+        stretched_audio_name(&old_path, rate).map(PathBuf::from)
+    };
+    Ok(Some(dest_dir.join(new_name.ok_or(AudioStretchError::InvalidSource)?)))
+}
+
+// Returns the file name of the audio at `path` stretched by `rate`, which looks like "audio.mp3" -> "audio_1.2.mp3"
+// for a rate of 1.2. The rate is kept as-is after the last underscore (which it never contains), so different audio
+// files and rates never get the same name, as they could if the dot was replaced (i.e. "audio_1.mp3" at 2x and
+// "audio.mp3" at 1.2x would both be "audio_1_2.mp3").
+fn stretched_audio_name(path: &Path, rate: f64) -> Option<String> {
+    let (stem, extension) = (path.file_stem()?.to_string_lossy(), path.extension()?.to_string_lossy());
+    Some(format!("{}_{}.{}", stem, rate, extension))
+}
+
+// Resolves the path of a beatmap's audio file from its `AudioFilename`, which is relative to the beatmap's directory
+// `dir`. The audio may be in a subdirectory, or even in a parent or sibling directory. Maps made on Windows may
+// separate directories with backslashes, so both kinds of slashes are accepted. Empty components (i.e. from a leading
+// slash) are skipped, which would otherwise make the path absolute, replacing `dir` entirely.
+pub fn audio_source_path(dir: &Path, audio_file: &str) -> PathBuf {
+    let components = audio_file.split(&['/', '\\'][..]).filter(|&c| !c.is_empty() && c != ".");
+    components.fold(dir.to_path_buf(), |path, c| path.join(c))
+}
+
+// Stretches the audio file at `old_path` by a factor of `rate` with `stretcher`, saving the output to `new_path`.
+pub fn stretch_file(old_path: &Path, new_path: &Path, rate: f64, stretcher: &dyn AudioStretcher) -> Result<()> {
+    let mut old_audio = File::open(old_path).or(Err(AudioStretchError::SourceNotFound))?;
+    util::verify(!util::is_same_file(old_path, new_path), AudioStretchError::DestinationIsSource)?;
+    util::write_atomically(new_path, AudioStretchError::DestinationIoError, |file| {
+        stretcher.stretch(&mut old_audio, file, rate)
+    })
+}
 
+// Stretches MP3 audio read from `src` by a factor of `rate`, writing the output to `dest` as MP3 audio. Resampling is
+// done with `n_threads` threads, or one per core if `None`. Like in osu!'s nightcore mod, the rate changes the pitch
+// along with the tempo; the pitch is then shifted by another `semitones` (which may be negative) without changing the
+// tempo any further, so the output is always `rate` times shorter than the input. If `loudness_target` is set, the
+// output is made as close to that integrated loudness (in LUFS) as it can be without clipping, and tagged with its
+// ReplayGain (see `normalize_loudness`). If `anti_alias` is set, audio that is sped up (including by the pitch shift)
+// is low-pass filtered before being resampled, which keeps frequencies that can't be represented after resampling from
+// aliasing (into quieter, but audible, lower frequencies).
+//
+// `src` must contain mono or stereo MP3 audio (an ID3v2 tag at the start is skipped), `rate` must be positive,
+// `semitones` must be finite, and `loudness_target` must be negative. The output is always stereo, with the same
+// sample rate as the input and a bitrate of at most 128 kbps. Nothing is written to `dest` unless the audio was decoded
+// and encoded successfully, so a `DestinationIoError` is the only error after which `dest` may have been partially
+// written to.
+pub fn stretch(
+    src: impl Read,
+    dest: &mut (impl Write + ?Sized),
+    rate: f64,
+    semitones: f64,
+    loudness_target: Option<f64>,
+    anti_alias: bool,
+    n_threads: Option<usize>,
+) -> Result<()> {
+    util::verify(rate > 0. && rate.is_finite(), AudioStretchError::InvalidRate)?;
+    util::verify(semitones.is_finite(), AudioStretchError::InvalidPitch)?;
+    let valid_target = loudness_target.into_iter().all(|t| t < 0. && t.is_finite());
+    util::verify(valid_target, AudioStretchError::InvalidLoudnessTarget)?;
+    let frames = decode(src)?;
     let channels = frames[0].channels;
     util::verify(channels <= 2, AudioStretchError::UnsupportedChannelCount)?;
     let sample_rate = frames[0].sample_rate;
@@ -69,36 +291,555 @@ fn stretch(src: impl Read, dest: &mut impl Write, rate: f64) -> Result<()> {
     let bitrate = frames[0].bitrate;
 
     // Gather samples from each frame and resample.
-    let samples = frames.into_iter().flat_map(|f| f.data).collect();
-    let concurrency = thread::available_concurrency().map(|n| n.get()).unwrap_or(2);
-    let (samples_l, samples_r) = resample_parallel(samples, rate, concurrency);
+    let mut samples = frames.into_iter().flat_map(|f| f.data).collect::<Vec<_>>();
+    let n_threads = n_threads.unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(2));
 
+    // To shift the pitch, the audio is resampled further (changing its tempo as well), then stretched back to the
+    // length given by `rate` alone.
+    let pitch = 2f64.powf(semitones / 12.);
+    if anti_alias && rate * pitch > 1. {
+        low_pass(&mut samples, channels, sample_rate as u32, rate * pitch);
+    }
+    let (samples_l, samples_r) = resample_parallel(samples, channels, rate * pitch, n_threads);
+    let (mut samples_l, mut samples_r) = if pitch == 1. {
+        (samples_l, samples_r)
+    } else {
+        (time_stretch(&samples_l, pitch), time_stretch(&samples_r, pitch))
+    };
+
+    let tag = match loudness_target {
+        Some(target) => normalize_loudness(&mut samples_l, &mut samples_r, sample_rate as u32, target),
+        _ => vec![],
+    };
+
+    // Encode the stretched PCM data to MP3, writing it (after the ReplayGain tag, if any) to `dest`.
+    let encoded = encode(&samples_l, &samples_r, sample_rate as u32, bitrate.min(128))?;
+    dest.write_all(&tag).and_then(|_| dest.write_all(&encoded)).or(Err(AudioStretchError::DestinationIoError))
+}
+
+// Encodes stereo PCM data to MP3 with the system LAME library.
+#[cfg(all(feature = "lame", not(feature = "bundled-lame")))]
+fn encode(samples_l: &[i16], samples_r: &[i16], sample_rate: u32, bitrate: i32) -> Result<Vec<u8>> {
     let mut lame = Lame::new().ok_or(AudioStretchError::LameInitializationError)?;
     lame.init_params()?;
-    lame.set_sample_rate(sample_rate as u32)?;
+    lame.set_sample_rate(sample_rate)?;
     lame.set_quality(9)?;
-    lame.set_kilobitrate(bitrate.min(128))?;
+    lame.set_kilobitrate(bitrate)?;
 
-    // Encode the stretched PCM data to MP3, writing it to `dest`.
     let mut buf = vec![0; samples_l.len()];
-    let written = lame.encode(&samples_l, &samples_r, &mut buf).or(Err(AudioStretchError::LameEncodingError))?;
-    dest.write_all(&buf[..written]).or(Err(AudioStretchError::DestinationIoError))
+    let written = lame.encode(samples_l, samples_r, &mut buf).or(Err(AudioStretchError::LameEncodingError))?;
+    buf.truncate(written);
+    Ok(buf)
+}
+
+// Encodes stereo PCM data to MP3 with the copy of LAME built into osurate. Its bitrate has to be one of the standard
+// MP3 bitrates, so the closest one not above `bitrate` is used.
+#[cfg(feature = "bundled-lame")]
+fn encode(samples_l: &[i16], samples_r: &[i16], sample_rate: u32, bitrate: i32) -> Result<Vec<u8>> {
+    let bitrate = match bitrate {
+        b if b >= 128 => Bitrate::Kbps128,
+        b if b >= 112 => Bitrate::Kbps112,
+        b if b >= 96 => Bitrate::Kbps96,
+        b if b >= 80 => Bitrate::Kbps80,
+        b if b >= 64 => Bitrate::Kbps64,
+        b if b >= 48 => Bitrate::Kbps48,
+        b if b >= 40 => Bitrate::Kbps40,
+        b if b >= 32 => Bitrate::Kbps32,
+        _ => Bitrate::Kbps24,
+    };
+
+    let config_error = |_| AudioStretchError::LameConfigurationError;
+    let mut builder = Builder::new().ok_or(AudioStretchError::LameInitializationError)?;
+    builder.set_num_channels(2).map_err(config_error)?;
+    builder.set_sample_rate(sample_rate).map_err(config_error)?;
+    builder.set_quality(Quality::Worst).map_err(config_error)?;
+    builder.set_brate(bitrate).map_err(config_error)?;
+    let mut lame = builder.build().map_err(config_error)?;
+
+    let input = DualPcm { left: samples_l, right: samples_r };
+    let mut buf = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(samples_l.len()));
+    let written = lame.encode(input, buf.spare_capacity_mut()).or(Err(AudioStretchError::LameEncodingError))?;
+    // The encoder has initialized this many bytes of the buffer.
+    unsafe { buf.set_len(written) };
+    buf.reserve(7200);
+    let written = lame.flush::<FlushNoGap>(buf.spare_capacity_mut()).or(Err(AudioStretchError::LameEncodingError))?;
+    unsafe { buf.set_len(buf.len() + written) };
+    Ok(buf)
+}
+
+// Decodes MP3 audio read from `src` into frames of i16 PCM data.
+fn decode(mut src: impl Read) -> Result<Vec<Frame>> {
+    let mut data = vec![];
+    src.read_to_end(&mut data).or(Err(AudioStretchError::InvalidSource))?;
+
+    let mut decoder = Decoder::new(&data[id3v2_tag_len(&data)..]);
+    let mut frames = vec![];
+    while let Ok(frame) = decoder.next_frame() {
+        frames.push(frame);
+    }
+    match decoder.next_frame() {
+        Err(minimp3::Error::Eof) | Err(minimp3::Error::SkippedData) => {}
+        _ => return Err(AudioStretchError::InvalidSource),
+    }
+
+    util::verify(!frames.is_empty(), AudioStretchError::InvalidSource)?;
+    Ok(frames)
+}
+
+// Returns the length of the ID3v2 tag at the start of `data`, or 0 if there is none. The tag is skipped before
+// decoding, since the decoder may mistake its contents for audio.
+fn id3v2_tag_len(data: &[u8]) -> usize {
+    if data.len() < 10 || !data.starts_with(b"ID3") {
+        return 0;
+    }
+
+    // The tag size excludes the header (and footer, if present) and is stored in four 7-bit bytes.
+    let size = data[6..10].iter().fold(0, |size, &b| size << 7 | (b & 0x7f) as usize);
+    let footer_size = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    (10 + size + footer_size).min(data.len())
+}
+
+// Resamples interleaved PCM `samples` with `channels` channels (one or two) by a factor of `rate` in parallel with
+// `n_threads` worker threads. Mono audio is duplicated into both of the returned channels. This is public so that it
+// can be benchmarked on its own, without decoding or encoding any MP3 audio.
+pub fn resample_parallel(samples: Vec<i16>, channels: usize, rate: f64, n_threads: usize) -> (Vec<i16>, Vec<i16>) {
+    // With only one thread (or if the thread pool can't be created), the samples are resampled in one go on the
+    // current thread.
+    let pool = Some(n_threads).filter(|&n| n > 1).and_then(|n| ThreadPoolBuilder::new().num_threads(n).build().ok());
+    let pool = match pool {
+        Some(pool) => pool,
+        _ => return resample_chunk(&samples, channels, rate).into_iter().unzip(),
+    };
+
+    // Split the samples into equally sized chunks of whole frames, one per thread, and recombine them in order.
+    let n_frames = (samples.len() as f64 / channels as f64 / n_threads as f64).ceil() as usize;
+    let chunks = samples.par_chunks(n_frames * channels);
+    pool.install(|| chunks.flat_map_iter(|c| resample_chunk(c, channels, rate)).unzip())
+}
+
+// Helper function to resample a chunk of PCM samples. The output has `ceil(n_frames / rate)` frames, where `n_frames`
+// is the number of frames in `samples`.
+fn resample_chunk(samples: &[i16], channels: usize, rate: f64) -> Vec<(i16, i16)> {
+    let len = (samples.len() as f64 / channels as f64 / rate).ceil() as usize;
+
+    // The interpolator needs a frame after the one it is on, so the last frame is repeated to keep the end of the chunk
+    // from being cut off.
+    let padding = samples[samples.len().saturating_sub(channels)..].iter().copied().cycle();
+    let samples = samples.iter().copied().chain(padding);
+    if channels == 1 {
+        let mut src = signal::from_interleaved_samples_iter::<_, [i16; 1]>(samples);
+        let lerp = Linear::new(src.next(), src.next());
+        src.scale_hz(lerp, rate).take(len).map(|[s]| (s, s)).collect()
+    } else {
+        let mut src = signal::from_interleaved_samples_iter::<_, [i16; 2]>(samples);
+        let lerp = Linear::new(src.next(), src.next());
+        src.scale_hz(lerp, rate).take(len).map(|[l, r]| (l, r)).collect()
+    }
+}
+
+// The length in samples of the grains used by `time_stretch` (~46 ms at 44.1 kHz), which is long enough to hold a few
+// periods of most notes, but short enough to not smear out the beat.
+const GRAIN_LEN: usize = 2048;
+
+// Lengthens PCM `samples` by a factor of `factor` without changing their pitch. This overlap-adds grains of the input,
+// weighted by a Hann window, which are read closer together (or further apart) than they are written. The output has
+// `ceil(samples.len() * factor)` samples.
+fn time_stretch(samples: &[i16], factor: f64) -> Vec<i16> {
+    let out_hop = GRAIN_LEN / 2;
+    let in_hop = out_hop as f64 / factor;
+    let window = (0..GRAIN_LEN).map(|i| 0.5 - 0.5 * (2. * PI * i as f64 / GRAIN_LEN as f64).cos());
+    let window = window.collect::<Vec<_>>();
+
+    // The output is built with an extra half grain in front, so that grain `n` is centered on input sample
+    // `n * in_hop` and output sample `n * out_hop`. Hann windows overlapping by half sum to 1, but grains near either
+    // end of the input are partly outside of it, so the window weights are summed up to keep the volume the same there.
+    let len = (samples.len() as f64 * factor).ceil() as usize;
+    let mut out = vec![(0., 0.); len + GRAIN_LEN + out_hop];
+    for (n, out_start) in (0..len + out_hop).step_by(out_hop).enumerate() {
+        let in_start = (n as f64 * in_hop) as isize - out_hop as isize;
+        for (i, w) in window.iter().enumerate() {
+            let j = in_start + i as isize;
+            if let Some(&sample) = if j < 0 { None } else { samples.get(j as usize) } {
+                let (sum, weight) = &mut out[out_start + i];
+                *sum += w * sample as f64;
+                *weight += w;
+            }
+        }
+    }
+
+    // Since the result is a weighted average of input samples, it can't clip.
+    let out = out[out_hop..out_hop + len].iter().map(|&(sum, weight)| if weight > 0. { sum / weight } else { 0. });
+    out.map(|s| s.round() as i16).collect()
+}
+
+// The resonances of the biquad sections that make up an 8th order Butterworth filter.
+const BUTTERWORTH_QS: [f64; 4] = [0.509_795_6, 0.601_344_9, 0.899_976_2, 2.562_915_4];
+
+// Filters interleaved PCM `samples` with `channels` channels to remove the frequencies that would alias when they're
+// resampled by a factor of `rate` (above 1), which are those above the Nyquist frequency of the resampled audio. The
+// cutoff is a bit below that, since the filter (an 8th order Butterworth filter) doesn't cut off sharply.
+fn low_pass(samples: &mut [i16], channels: usize, sample_rate: u32, rate: f64) {
+    let cutoff = 0.9 * sample_rate as f64 / 2. / rate;
+    let filters = || BUTTERWORTH_QS.map(|q| Biquad::low_pass(sample_rate, cutoff, q));
+    let mut filters = (0..channels).map(|_| filters()).collect::<Vec<_>>();
+    for frame in samples.chunks_mut(channels) {
+        for (sample, filters) in frame.iter_mut().zip(&mut filters) {
+            *sample = filters.iter_mut().fold(*sample as f64, |x, filter| filter.process(x)).round() as i16;
+        }
+    }
+}
+
+// The loudness that ReplayGain 2.0 adjusts tracks to, in LUFS.
+const REPLAY_GAIN_REFERENCE: f64 = -18.;
+
+// Applies a gain to stereo PCM data to bring its integrated loudness to `target` (in LUFS), and returns an ID3v2 tag
+// with the ReplayGain of the result. The gain is limited so that the loudest sample doesn't clip, in which case the
+// output will be quieter than `target`. Silent audio is left alone, and no tag is returned for it.
+fn normalize_loudness(samples_l: &mut [i16], samples_r: &mut [i16], sample_rate: u32, target: f64) -> Vec<u8> {
+    let loudness = match integrated_loudness(samples_l, samples_r, sample_rate) {
+        Some(loudness) => loudness,
+        _ => return vec![],
+    };
+    let peak = samples_l.iter().chain(samples_r.iter()).map(|s| s.unsigned_abs()).max().unwrap_or(0).max(1);
+    let gain = 10f64.powf((target - loudness) / 20.).min(i16::MAX as f64 / peak as f64);
+    for sample in samples_l.iter_mut().chain(samples_r.iter_mut()) {
+        *sample = (*sample as f64 * gain).round() as i16;
+    }
+
+    let loudness = loudness + 20. * gain.log10();
+    let peak = (peak as f64 * gain / 32_768.).min(1.);
+    replay_gain_tag(REPLAY_GAIN_REFERENCE - loudness, peak)
+}
+
+// Returns the integrated loudness in LUFS of stereo PCM data, as defined by ITU-R BS.1770 (which EBU R 128 is based
+// on), or `None` if the audio is (almost) silent.
+fn integrated_loudness(samples_l: &[i16], samples_r: &[i16], sample_rate: u32) -> Option<f64> {
+    // The mean squares of 400 ms blocks overlapping by 75% are gated. These are built out of 100 ms segments.
+    let segment_len = (sample_rate as usize / 10).max(1);
+    let mut segments = vec![0.; samples_l.len() / segment_len];
+    for samples in &[samples_l, samples_r] {
+        let (mut shelf, mut high_pass) = (Biquad::high_shelf(sample_rate), Biquad::high_pass(sample_rate));
+        let filtered = samples.iter().map(|&s| high_pass.process(shelf.process(s as f64 / 32_768.)));
+        for (segment, filtered) in segments.iter_mut().zip(filtered.collect::<Vec<_>>().chunks_exact(segment_len)) {
+            *segment += filtered.iter().map(|s| s * s).sum::<f64>();
+        }
+    }
+    let blocks = segments.windows(4).map(|w| w.iter().sum::<f64>() / (4 * segment_len) as f64).collect::<Vec<_>>();
+
+    // Blocks quieter than -70 LUFS are ignored, then so are the blocks 10 LU quieter than the remaining ones.
+    let loudness = |mean_square: f64| -0.691 + 10. * mean_square.log10();
+    let gated_loudness = |threshold: f64| {
+        let gated = blocks.iter().filter(|&&b| loudness(b) > threshold).collect::<Vec<_>>();
+        Some(loudness(gated.iter().copied().sum::<f64>() / gated.len() as f64)).filter(|_| !gated.is_empty())
+    };
+    gated_loudness(-70.).and_then(|l| gated_loudness(l - 10.))
+}
+
+// A biquad filter, used for the K-weighting of `integrated_loudness` and the anti-aliasing of `low_pass`. The
+// coefficients are normalized so that `a[0]` is 1.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    // Models the acoustic effect of the head, boosting frequencies above ~1.5 kHz by ~4 dB. This and `high_pass` use
+    // the coefficients of ITU-R BS.1770, derived for any sample rate (giving the ones in the standard at 48 kHz).
+    fn high_shelf(sample_rate: u32) -> Self {
+        let (k, q) = ((PI * 1_681.974_450_955_533 / sample_rate as f64).tan(), 0.707_175_236_955_419_6);
+        let vh = 10f64.powf(3.999_843_853_973_347 / 20.);
+        let vb = vh.powf(0.499_666_774_154_541_6);
+        let b = [vh + vb * k / q + k * k, 2. * (k * k - vh), vh - vb * k / q + k * k];
+        Self::new(b, [1. + k / q + k * k, 2. * (k * k - 1.), 1. - k / q + k * k])
+    }
+
+    // Cuts off frequencies below ~40 Hz, which are barely heard. As in the standard, `b` is [1, -2, 1] once normalized,
+    // instead of being scaled for a gain of exactly 1.
+    fn high_pass(sample_rate: u32) -> Self {
+        let (k, q) = ((PI * 38.135_470_876_024_44 / sample_rate as f64).tan(), 0.500_327_037_323_877_3);
+        let a0 = 1. + k / q + k * k;
+        Self::new([a0, -2. * a0, a0], [a0, 2. * (k * k - 1.), 1. - k / q + k * k])
+    }
+
+    // Cuts off frequencies above `cutoff` (in Hz), with a resonance of `q` (see the Audio EQ Cookbook).
+    fn low_pass(sample_rate: u32, cutoff: f64, q: f64) -> Self {
+        let w0 = 2. * PI * cutoff / sample_rate as f64;
+        let (cos, alpha) = (w0.cos(), w0.sin() / (2. * q));
+        Self::new([(1. - cos) / 2., 1. - cos, (1. - cos) / 2.], [1. + alpha, -2. * cos, 1. - alpha])
+    }
+
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Biquad { b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]], a: [1., a[1] / a[0], a[2] / a[0]], state: [0.; 2] }
+    }
+
+    // Filters the next sample (in transposed direct form II).
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state = [self.b[1] * x - self.a[1] * y + self.state[1], self.b[2] * x - self.a[2] * y];
+        y
+    }
 }
 
-// Resamples dual channel PCM `samples` by a factor of `rate` in parallel with `n_threads` worker threads.
-fn resample_parallel(samples: Vec<i16>, rate: f64, n_threads: usize) -> (Vec<i16>, Vec<i16>) {
-    // Split the samples into equally sized chunks and spawn a thread to process each.
-    let n_chunks = (samples.len() as f64 / n_threads as f64).ceil() as usize;
-    let chunks = samples.chunks(n_chunks).map(|c| c.to_vec());
-    let handles = chunks.map(|c| thread::spawn(move || resample_chunk(c, rate))).collect::<Vec<_>>();
+// Returns an ID3v2.3 tag holding `gain` (in dB) and `peak` (relative to full scale) as the ReplayGain of a track, the
+// way most players (and other taggers) expect.
+fn replay_gain_tag(gain: f64, peak: f64) -> Vec<u8> {
+    let gain = ("REPLAYGAIN_TRACK_GAIN", format!("{:+.2} dB", gain));
+    let fields = [gain, ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", peak))];
+    let mut frames = vec![];
+    for (description, value) in &fields {
+        // A user-defined text frame, with its text encoded in ISO-8859-1.
+        let content = [&[0][..], description.as_bytes(), &[0], value.as_bytes()].concat();
+        frames.extend(b"TXXX");
+        frames.extend(&(content.len() as u32).to_be_bytes());
+        frames.extend(&[0, 0]);
+        frames.extend(content);
+    }
 
-    // Recombine the resampled chunks.
-    handles.into_iter().map(|h| h.join().unwrap()).flatten().unzip()
+    // The tag size is stored in four 7-bit bytes (see `id3v2_tag_len`).
+    let size = frames.len();
+    let size = [(size >> 21) as u8 & 0x7f, (size >> 14) as u8 & 0x7f, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f];
+    [&b"ID3\x03\x00\x00"[..], &size, &frames].concat()
 }
 
-// Helper function to resample a chunk of PCM samples.
-fn resample_chunk(samples: Vec<i16>, rate: f64) -> Vec<(i16, i16)> {
-    let mut src = signal::from_interleaved_samples_iter::<_, [i16; 2]>(samples);
-    let lerp = Linear::new(src.next(), src.next());
-    src.scale_hz(lerp, rate).until_exhausted().map(|[l, r]| (l, r)).collect()
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::path::Path;
+
+    use crate::audio;
+    use crate::beatmap::parse_fixture;
+    use crate::test_util::TestDir;
+
+    // Returns `n` frames of silent 128 kbps 44.1 kHz stereo MP3 audio. Each frame is a header followed by zeroed side
+    // information and main data.
+    fn silent_mp3(n: usize) -> Vec<u8> {
+        let mut frame = vec![0; 417];
+        frame[..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0x00]);
+        frame.repeat(n)
+    }
+
+    #[test]
+    fn decode_skips_id3v2_tag() {
+        // A 417 byte tag whose contents happen to be a valid frame, which shouldn't be decoded.
+        let mut tagged = b"ID3\x04\x00\x00\x00\x00\x03\x21".to_vec();
+        tagged.extend(silent_mp3(9));
+
+        let frames = audio::decode(tagged.as_slice()).unwrap();
+        assert_eq!(frames.len(), 8);
+        assert!(frames.iter().all(|f| f.channels == 2 && f.sample_rate == 44_100));
+    }
+
+    #[test]
+    fn resample_mono_keeps_length() {
+        let samples = (0..4000).collect::<Vec<_>>();
+        let (samples_l, samples_r) = audio::resample_parallel(samples, 1, 1., 4);
+        assert_eq!(samples_l, samples_r);
+        assert!((3990..=4000).contains(&samples_l.len()));
+    }
+
+    #[test]
+    fn resample_stereo_keeps_channels_apart() {
+        // Odd chunk sizes previously split frames across threads, swapping the channels of every other chunk.
+        let samples = (0..3000).flat_map(|_| [100, -100]).collect::<Vec<_>>();
+        let (samples_l, samples_r) = audio::resample_parallel(samples, 2, 1., 7);
+        assert!(samples_l.iter().all(|&s| s == 100) && samples_r.iter().all(|&s| s == -100));
+    }
+
+    #[test]
+    fn resample_single_threaded_keeps_length() {
+        let samples = (0..3000).flat_map(|i| [i, -i]).collect::<Vec<_>>();
+        let serial = audio::resample_parallel(samples.clone(), 2, 1., 1);
+        assert_eq!(serial, audio::resample_parallel(samples, 2, 1., 0));
+        assert!((2995..=3000).contains(&serial.0.len()));
+    }
+
+    #[test]
+    fn resample_chunk_output_length_matches_rate() {
+        for &rate in &[0.5, 0.75, 1., 1.2, 1.5, 2., 3.0_f64] {
+            let samples = (0..2000).collect::<Vec<_>>();
+            let expected = (1000. / rate).ceil() as usize;
+            let resampled = audio::resample_chunk(&samples, 2, rate);
+            assert_eq!(resampled.len(), expected, "rate {}", rate);
+            assert_eq!(resampled[0], (0, 1));
+        }
+    }
+
+    #[test]
+    fn resample_chunk_keeps_the_end() {
+        let samples = (0..1000).collect::<Vec<_>>();
+        assert_eq!(audio::resample_chunk(&samples, 1, 1.).last(), Some(&(999, 999)));
+        assert!(audio::resample_chunk(&[], 2, 1.2).is_empty());
+    }
+
+    #[test]
+    fn decode_untagged() {
+        assert_eq!(audio::decode(silent_mp3(8).as_slice()).unwrap().len(), 8);
+    }
+
+    // Copies its input, keeping only every `rate`th byte. Only used to check that other backends are called through.
+    struct ByteSkipper;
+
+    impl audio::AudioStretcher for ByteSkipper {
+        fn stretch(&self, src: &mut dyn Read, dest: &mut dyn Write, rate: f64) -> audio::Result<()> {
+            let mut data = vec![];
+            src.read_to_end(&mut data).unwrap();
+            let stretched = data.into_iter().step_by(rate as usize).collect::<Vec<_>>();
+            dest.write_all(&stretched).or(Err(audio::AudioStretchError::DestinationIoError))
+        }
+    }
+
+    #[test]
+    fn stretch_file_uses_the_given_stretcher() {
+        let dir = TestDir::new("stretch_file");
+        fs::write(dir.join("audio.mp3"), b"abcdef").unwrap();
+
+        audio::stretch_file(&dir.join("audio.mp3"), &dir.join("audio_2.mp3"), 2., &ByteSkipper).unwrap();
+        assert_eq!(fs::read(dir.join("audio_2.mp3")).unwrap(), b"ace");
+    }
+
+    #[test]
+    fn stretch_rejects_invalid_rates() {
+        for &rate in &[0., -1.2, f64::NAN, f64::INFINITY] {
+            let result = audio::stretch(silent_mp3(8).as_slice(), &mut vec![], rate, 0., None, true, Some(1));
+            assert!(matches!(result, Err(audio::AudioStretchError::InvalidRate)));
+        }
+        let result = audio::stretch(silent_mp3(8).as_slice(), &mut vec![], 1.2, f64::NAN, None, true, Some(1));
+        assert!(matches!(result, Err(audio::AudioStretchError::InvalidPitch)));
+        let result = audio::stretch(silent_mp3(8).as_slice(), &mut vec![], 1.2, 0., Some(3.), true, Some(1));
+        assert!(matches!(result, Err(audio::AudioStretchError::InvalidLoudnessTarget)));
+    }
+
+    // Returns `n` samples of a 1 kHz sine wave at 48 kHz with a peak of `amplitude` (relative to full scale).
+    fn sine(amplitude: f64, n: usize) -> Vec<i16> {
+        let sine = (0..n).map(|i| (i as f64 * 2. * std::f64::consts::PI / 48.).sin() * amplitude * 32_767.);
+        sine.map(|s| s.round() as i16).collect()
+    }
+
+    #[test]
+    fn integrated_loudness_matches_reference() {
+        // Full scale 1 kHz sine waves in both channels measure 0 LUFS, and the loudness is relative to that.
+        let (l, r) = (sine(0.1, 48_000 * 3), sine(0.1, 48_000 * 3));
+        assert!((audio::integrated_loudness(&l, &r, 48_000).unwrap() + 20.).abs() < 0.05);
+        assert!((audio::integrated_loudness(&l, &[0; 48_000 * 3], 48_000).unwrap() + 23.01).abs() < 0.05);
+        assert_eq!(audio::integrated_loudness(&[0; 48_000], &[0; 48_000], 48_000), None);
+    }
+
+    #[test]
+    fn normalize_loudness_avoids_clipping() {
+        let (mut l, mut r) = (sine(0.1, 48_000 * 3), sine(0.1, 48_000 * 3));
+        let tag = audio::normalize_loudness(&mut l, &mut r, 48_000, -14.);
+        assert!((audio::integrated_loudness(&l, &r, 48_000).unwrap() + 14.).abs() < 0.05);
+        assert_eq!(audio::id3v2_tag_len(&tag), tag.len());
+        assert!(String::from_utf8_lossy(&tag).contains("REPLAYGAIN_TRACK_GAIN\0-4.00 dB"));
+
+        // With a spike at ~60% of full scale, the gain needed would make it clip.
+        let (mut l, mut r) = (sine(0.1, 48_000 * 3), sine(0.1, 48_000 * 3));
+        l[1000] = 20_000;
+        let tag = audio::normalize_loudness(&mut l, &mut r, 48_000, -14.);
+        assert_eq!(l[1000], i16::MAX);
+        assert!(String::from_utf8_lossy(&tag).contains("REPLAYGAIN_TRACK_PEAK\x000.999969"));
+    }
+
+    #[cfg(feature = "ffmpeg")]
+    #[test]
+    fn ffmpeg_filters_keep_the_tempo_when_shifting_the_pitch() {
+        assert_eq!(audio::ffmpeg_filters(1.5, 0.), "aresample=44100,asetrate=66150,aresample=44100");
+        let filters = audio::ffmpeg_filters(1., 24.);
+        assert_eq!(filters, "aresample=44100,asetrate=176400,aresample=44100,atempo=0.5,atempo=0.5");
+        assert!(audio::ffmpeg_filters(1.2, -3.).ends_with(",atempo=1.189207115002721"));
+    }
+
+    #[cfg(feature = "ffmpeg")]
+    #[test]
+    fn ffmpeg_falls_back_to_native_backend() {
+        let executable = Some("osurate-missing-ffmpeg".into());
+        let stretcher = audio::FfmpegStretcher { executable, ..Default::default() };
+        let result = audio::AudioStretcher::stretch(&stretcher, &mut &b"not an mp3 file"[..], &mut vec![], 1.2);
+        assert!(matches!(result, Err(audio::AudioStretchError::InvalidSource)));
+    }
+
+    #[test]
+    fn low_pass_removes_frequencies_that_would_alias() {
+        let rms = |s: &[i16]| (s.iter().map(|&s| (s as f64).powi(2)).sum::<f64>() / s.len() as f64).sqrt();
+        let tone = |freq: f64| (0..48_000).map(move |i| (i as f64 * 2. * std::f64::consts::PI * freq / 48_000.).sin());
+        let tone = |freq: f64| tone(freq).map(|s| (s * 10_000.) as i16).collect::<Vec<_>>();
+
+        // At 2x, everything above 12 kHz would alias, so a 1 kHz tone is kept, but a 16 kHz one is removed (past the
+        // start, where the filter settles).
+        let (mut low, mut high) = (tone(1_000.), tone(16_000.));
+        let (low_rms, high_rms) = (rms(&low), rms(&high));
+        audio::low_pass(&mut low, 1, 48_000, 2.);
+        audio::low_pass(&mut high, 1, 48_000, 2.);
+        assert!((rms(&low[1000..]) / low_rms - 1.).abs() < 0.01);
+        assert!(rms(&high[1000..]) / high_rms < 0.05);
+    }
+
+    #[test]
+    fn time_stretch_keeps_volume() {
+        for &factor in &[0.5, 0.8, 1.5] {
+            let stretched = audio::time_stretch(&[1000; 10_000], factor);
+            assert_eq!(stretched.len(), (10_000. * factor).ceil() as usize);
+            assert!(stretched.iter().all(|&s| s == 1000));
+        }
+    }
+
+    #[test]
+    fn audio_source_path_handles_subdirectories() {
+        let dir = Path::new("maps").join("set");
+        assert_eq!(audio::audio_source_path(&dir, "audio.mp3"), dir.join("audio.mp3"));
+        assert_eq!(audio::audio_source_path(&dir, "sub/audio.mp3"), dir.join("sub").join("audio.mp3"));
+        assert_eq!(audio::audio_source_path(&dir, "/./sub//audio.mp3"), dir.join("sub").join("audio.mp3"));
+        assert_eq!(audio::audio_source_path(&dir, "sub\\audio.mp3"), dir.join("sub").join("audio.mp3"));
+        let sibling = dir.join("..").join("other").join("audio.mp3");
+        assert_eq!(audio::audio_source_path(&dir, "../other/audio.mp3"), sibling);
+    }
+
+    #[test]
+    fn stretched_audio_names_dont_collide() {
+        let name = |path, rate| audio::stretched_audio_name(Path::new(path), rate).unwrap();
+        assert_eq!(name("audio.mp3", 1.2), "audio_1.2.mp3");
+        assert_ne!(name("audio.mp3", 1.2), name("audio_1.mp3", 2.));
+        assert_ne!(name("audio.mp3", 1.2), name("audio.mp3", 12.));
+        assert_eq!(name("song.final.ogg", 0.85), "song.final_0.85.ogg");
+        assert_eq!(audio::stretched_audio_name(Path::new("audio"), 1.2), None);
+    }
+
+    #[test]
+    fn audio_in_a_subdirectory_is_copied_next_to_the_map() {
+        let dir = TestDir::new("audio_subdirectory");
+        fs::create_dir_all(dir.join("set").join("sub")).unwrap();
+        fs::create_dir_all(dir.join("out")).unwrap();
+        fs::write(dir.join("set").join("sub").join("audio.mp3"), b"audio").unwrap();
+
+        let mut map = parse_fixture("standard");
+        map.general_info.audio_file = "sub/audio.mp3".to_string();
+        let stretcher = audio::Mp3Stretcher::default();
+        let new_path = audio::stretch_beatmap_audio(&mut map, &dir.join("set"), &dir.join("out"), 1., &stretcher);
+
+        assert_eq!(new_path.unwrap(), Some(dir.join("out").join("audio.mp3")));
+        assert_eq!(map.general_info.audio_file, "audio.mp3");
+        assert_eq!(fs::read(dir.join("out").join("audio.mp3")).unwrap(), b"audio");
+    }
+
+    #[test]
+    fn audio_with_backslashes_is_found() {
+        let dir = TestDir::new("audio_backslashes");
+        fs::create_dir_all(dir.join("sounds")).unwrap();
+        fs::create_dir_all(dir.join("out")).unwrap();
+        fs::write(dir.join("sounds").join("track.mp3"), b"audio").unwrap();
+
+        // The original audio is reused at 1.0x, so the map should keep referencing it as it did.
+        let mut map = parse_fixture("standard");
+        map.general_info.audio_file = "sounds\\track.mp3".to_string();
+        let stretcher = audio::Mp3Stretcher::default();
+        assert_eq!(audio::stretch_beatmap_audio(&mut map, &dir, &dir, 1., &stretcher).unwrap(), None);
+        assert_eq!(map.general_info.audio_file, "sounds\\track.mp3");
+
+        // The audio can only be copied if the backslash is treated as a separator.
+        let new_path = audio::stretch_beatmap_audio(&mut map, &dir, &dir.join("out"), 1., &stretcher).unwrap();
+        assert_eq!(new_path, Some(dir.join("out").join("track.mp3")));
+        assert_eq!(fs::read(dir.join("out").join("track.mp3")).unwrap(), b"audio");
+    }
 }
@@ -1,15 +1,23 @@
+use std::{error, fmt, result};
 use std::io::BufRead;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 pub use crate::beatmap::parser::ParseError;
 use crate::beatmap::parser::Parser;
+use crate::util;
 
 mod parser;
+pub mod storyboard;
 
 // Beatmap representation with only the necessary information for changing the rate of the map. Unused data is
 // collectively stored in the `rest` field of a given struct (if present). Alternatively, if the entire section is
 // unnecessary, the struct is a simple wrapper around the string contents of that section.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Beatmap {
+    pub format_version: u32,
     pub general_info: GeneralInfo,
     pub editor_info: EditorInfo,
     pub metadata: Metadata,
@@ -25,76 +33,256 @@ impl Beatmap {
         Parser::new(reader).parse()
     }
 
-    // Changes the rate of the beatmap from 1.0 to `rate`. This does not change the audio nor the audio metadata.
-    pub fn change_rate(&mut self, rate: f64) -> bool {
-        // The stretched audio seems to have a ~75 ms delay.
-        let transform_f64 = |n| n / rate + 75.;
-        let transform = |n| transform_f64(n as f64) as i32;
+    // Changes the rate of the beatmap from 1.0 to `rate`. This does not change the audio nor the audio metadata. The
+    // beatmap is left unchanged if an error is returned. Hit objects that end up before the audio starts aren't an
+    // error, since the map is still playable (see `hit_objects_before_start`).
+    //
+    // This applies `apply_objects`, `apply_metadata`, and `apply_timing`, which can also be used on their own (i.e. to
+    // only rename a map whose timing was changed by hand), but don't check the rate like this does.
+    pub fn change_rate(&mut self, rate: f64, options: &RateOptions) -> result::Result<(), ChangeRateError> {
+        util::verify(rate > 0. && rate.is_finite(), ChangeRateError::InvalidRate)?;
+
+        // Times are saved as 32-bit integers, which low rates of very long maps can overflow. The transform is a bit
+        // larger than the actual change for durations (i.e. the lead-in), which only errs on the side of caution.
+        let in_range = |t: f64| (i32::MIN as f64..=i32::MAX as f64).contains(&transform_time(t, rate, options));
+        let unchanged = rate == 1. && options.offset == 0.;
+        util::verify(unchanged || self.times().all(in_range), ChangeRateError::TimeOutOfRange)?;
+
+        // The times of events are only checked as they're changed, so the objects go first, before anything else is.
+        self.apply_objects(rate, options)?;
+        self.apply_metadata(rate, options);
+        self.apply_timing(rate, options);
+        Ok(())
+    }
 
-        // Change relevant metadata.
-        let preview = self.general_info.preview_time;
-        self.general_info.preview_time = if preview >= 0 { transform(preview) } else { preview };
-        self.metadata.diff_name += &format!(" ({}x)", rate);
+    // Changes the metadata of the beatmap for a rate of `rate`, which is the difficulty name (unless
+    // `options.keep_diff_name` is set) and the preview time (see `change_preview_time`).
+    pub fn apply_metadata(&mut self, rate: f64, options: &RateOptions) {
+        if !options.keep_diff_name {
+            self.metadata.diff_name += &format!(" ({}x)", rate);
+        }
+        self.change_preview_time(rate, options);
+    }
 
-        for mut point in &mut self.timing_points {
-            point.time = transform_f64(point.time);
+    // Changes the audio lead-in and timing points of the beatmap from 1.0 to `rate`. At 1.0x, only `options.offset` is
+    // applied (see `transform_time`).
+    pub fn apply_timing(&mut self, rate: f64, options: &RateOptions) {
+        // Unlike the other times, the lead-in is a duration, so the delay and offset don't apply to it.
+        self.general_info.audio_lead_in = (self.general_info.audio_lead_in as f64 / rate) as i32;
+
+        // Inherited timing points apply to the uninherited point before them, so they have to stay in the same order,
+        // which the transform ensures for the same reason as with hit objects (see `apply_objects`).
+        let parents = self.timing_point_parents();
+        for point in &mut self.timing_points {
+            point.time = transform_time(point.time, rate, options);
 
             // Only re-time uninherited timing points.
             if point.beat_len.is_sign_positive() {
                 point.beat_len /= rate;
             }
         }
+        debug_assert!(parents == self.timing_point_parents(), "inherited timing points were moved to another parent");
+    }
+
+    // Changes the times of the hit objects and events (i.e. videos and breaks) of the beatmap from 1.0 to `rate`. Like
+    // with `apply_timing`, only `options.offset` is applied at 1.0x. If an event would end up at a time out of range
+    // (see `storyboard::change_rate`), nothing changes and an error is returned.
+    pub fn apply_objects(&mut self, rate: f64, options: &RateOptions) -> result::Result<(), ChangeRateError> {
+        self.events = Events(storyboard::change_events_rate(&self.events.0, rate, options)?);
 
-        for mut object in &mut self.hit_objects {
-            object.time = transform(object.time);
+        // osu! assumes that hit objects are sorted by time. Since the transform is monotonic and times are only
+        // truncated when written, it should never reorder them.
+        let were_sorted = self.hit_objects_are_sorted();
+        let transform_f64 = |t| transform_time(t, rate, options);
+        for object in &mut self.hit_objects {
+            object.time = transform_f64(object.time);
 
             // Change the end times for relevant hit objects.
-            match object.params {
-                HitObjectParams::Spinner(end_time) => object.params = HitObjectParams::Spinner(transform(end_time)),
-                HitObjectParams::LongNote(end_time) => {
-                    // Small hack to make up for a lack of forethought in data storage.
-                    let rest = match object.rest_parts[2].split_once(':') {
-                        Some((_, rest)) => rest,
-                        _ => return false,
-                    };
-                    object.rest_parts[2] = transform(end_time).to_string() + ":" + rest;
-                }
-                _ => {}
-            }
+            object.params = match object.params {
+                HitObjectParams::Spinner(end_time) => HitObjectParams::Spinner(transform_f64(end_time)),
+                HitObjectParams::LongNote(end_time) => HitObjectParams::LongNote(transform_f64(end_time)),
+                HitObjectParams::NoneUseful => HitObjectParams::NoneUseful,
+            };
+        }
+        debug_assert!(!were_sorted || self.hit_objects_are_sorted(), "hit objects were reordered");
+        Ok(())
+    }
+
+    // Returns every time (and duration) in the beatmap that `change_rate` changes.
+    fn times(&self) -> impl Iterator<Item = f64> + '_ {
+        let (lead_in, preview) = (self.general_info.audio_lead_in as f64, self.general_info.preview_time as f64);
+        let timing_points = self.timing_points.iter().map(|p| p.time);
+        let hit_objects = self.hit_objects.iter().flat_map(|object| {
+            let end_time = match object.params {
+                HitObjectParams::Spinner(end_time) | HitObjectParams::LongNote(end_time) => Some(end_time),
+                HitObjectParams::NoneUseful => None,
+            };
+            std::iter::once(object.time).chain(end_time)
+        });
+        std::iter::once(lead_in).chain(Some(preview)).chain(timing_points).chain(hit_objects)
+    }
+
+    // Returns the number of hit objects that start before the audio does, which can't be hit.
+    pub fn hit_objects_before_start(&self) -> usize {
+        self.hit_objects.iter().filter(|object| object.time < 0.).count()
+    }
+
+    // Returns the number of inherited timing points with a slider velocity multiplier outside of the 0.1x to 10x that
+    // osu! allows, which it clamps. Since `change_rate` only scales uninherited timing points, the multipliers never
+    // change, so this is the same before and after rating.
+    pub fn slider_velocities_out_of_range(&self) -> usize {
+        let inherited = self.timing_points.iter().filter(|p| p.beat_len.is_sign_negative());
+        inherited.map(|p| -100. / p.beat_len).filter(|m| !(0.1..=10.).contains(m)).count()
+    }
+
+    // Returns the index of the uninherited timing point each inherited timing point applies to, which is the last one
+    // before or at the same time as it, or `None` if there is none.
+    fn timing_point_parents(&self) -> Vec<Option<usize>> {
+        let points = self.timing_points.iter().enumerate();
+        let uninherited = points.filter(|(_, p)| p.beat_len.is_sign_positive()).map(|(i, p)| (i, p.time as i32));
+        let uninherited = uninherited.collect::<Vec<_>>();
+        let parent = |p: &TimingPoint| {
+            uninherited.iter().rev().find(|(_, time)| *time <= p.time as i32).map(|(i, _)| *i)
+        };
+        self.timing_points.iter().filter(|p| p.beat_len.is_sign_negative()).map(parent).collect()
+    }
+
+    fn hit_objects_are_sorted(&self) -> bool {
+        self.hit_objects.windows(2).all(|pair| pair[0].time <= pair[1].time)
+    }
+
+    // Changes only the preview time of the beatmap, as `change_rate` does.
+    pub fn change_preview_time(&mut self, rate: f64, options: &RateOptions) {
+        // A negative preview time means that there is none, and like in `change_rate`, only the offset applies at 1.0x.
+        let preview = self.general_info.preview_time as f64;
+        if preview >= 0. {
+            let delay = if options.exact_preview_time { 0. } else { audio_delay(rate) };
+            self.general_info.preview_time = (preview / rate + delay + options.offset) as i32;
         }
-        true
+    }
+
+    // Returns the time at which the last hit object ends, in milliseconds. Sliders are treated as ending where they
+    // start, since their lengths aren't parsed.
+    pub fn length(&self) -> f64 {
+        let end_times = self.hit_objects.iter().map(|object| match object.params {
+            HitObjectParams::Spinner(end_time) | HitObjectParams::LongNote(end_time) => end_time,
+            HitObjectParams::NoneUseful => object.time,
+        });
+        end_times.fold(0., f64::max)
+    }
+
+    // Returns the BPM of the first uninherited timing point, which is what osu! shows as the map's BPM (and scales
+    // relative to), or `None` if there is none (which the parser rejects).
+    pub fn primary_bpm(&self) -> Option<f64> {
+        self.bpms().next()
+    }
+
+    // Returns the lowest and highest BPMs of the uninherited timing points, or `None` if there are none.
+    pub fn bpm_range(&self) -> Option<(f64, f64)> {
+        let range = |(min, max): (f64, f64), bpm: f64| (min.min(bpm), max.max(bpm));
+        self.primary_bpm().map(|bpm| self.bpms().fold((bpm, bpm), range))
+    }
+
+    fn bpms(&self) -> impl Iterator<Item = f64> + '_ {
+        let uninherited = self.timing_points.iter().filter(|p| p.beat_len.is_sign_positive());
+        uninherited.map(|p| 60_000. / p.beat_len)
+    }
+
+    // Returns the rate in the " (1.2x)" suffix that `change_rate` adds to the difficulty name, if there is one. Such a
+    // map was most likely generated by osurate, so rating it again would stack both rate changes.
+    pub fn rate_suffix(&self) -> Option<f64> {
+        let (_, rate) = self.metadata.diff_name.strip_suffix("x)")?.rsplit_once(" (")?;
+        rate.parse().ok().filter(|r: &f64| r.is_finite() && *r > 0.)
     }
 
     // Converts the beatmap into its textual representation.
     pub fn into_string(self) -> String {
         format!(
-            "osu file format v14\n\n{}\n{}\n{}\n{}\n{}\n[TimingPoints]\n{}\n\n{}\n[HitObjects]\n{}",
+            "osu file format v{}\n\n{}\n{}\n{}\n{}\n{}\n[TimingPoints]\n{}\n\n{}\n[HitObjects]\n{}",
+            self.format_version,
             self.general_info.into_string(),
             self.editor_info.into_string(),
             self.metadata.into_string(),
             self.difficulty.into_string(),
             self.events.into_string(),
             self.timing_points.into_iter().map(|p| p.into_string()).collect::<Vec<_>>().join("\n"),
-            self.colors.map(|c| c.into_string()).unwrap_or(String::new()),
+            self.colors.map(|c| c.into_string()).unwrap_or_default(),
             self.hit_objects.into_iter().map(|p| p.into_string()).collect::<Vec<_>>().join("\n"),
         )
     }
 }
 
+#[derive(Debug)]
+pub enum ChangeRateError {
+    InvalidRate,
+    TimeOutOfRange,
+}
+
+impl fmt::Display for ChangeRateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChangeRateError::InvalidRate => "rate must be positive",
+            ChangeRateError::TimeOutOfRange => "rate would make the map too long for osu! (try a higher rate)",
+        })
+    }
+}
+
+impl error::Error for ChangeRateError {}
+
+// The stretched audio seems to have a ~75 ms delay.
+const AUDIO_DELAY: f64 = 75.;
+
+// Returns the delay of the audio used at `rate`. At 1.0x, the original audio is reused instead of being re-encoded (see
+// `audio::stretch_beatmap_audio`), so there is no delay to compensate for.
+fn audio_delay(rate: f64) -> f64 {
+    if rate == 1. { 0. } else { AUDIO_DELAY }
+}
+
+// Settings for `Beatmap::change_rate`.
+#[derive(Clone, Debug, Default)]
+pub struct RateOptions {
+    // If set, the preview time is only scaled by the rate, without the delay of the stretched audio being added. This
+    // keeps the preview time proportional to the length of the map, but since the preview plays the stretched audio,
+    // the default (with the delay) is what keeps it at the same point in the song.
+    pub exact_preview_time: bool,
+
+    // An extra offset in milliseconds added to every changed time (including the preview time), for audio which is
+    // delayed differently, i.e. if it was stretched with another encoder.
+    pub offset: f64,
+
+    // If set, the difficulty name is left as-is instead of having the rate appended to it (i.e. "Insane (1.2x)").
+    pub keep_diff_name: bool,
+}
+
+// Since the order of keys is preserved on output, `rest` holds every line of the section. The values of the typed
+// fields are substituted back in by `into_string`.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct GeneralInfo {
     pub audio_file: String,
     pub preview_time: i32,
+    pub audio_lead_in: i32, // Milliseconds of silence before the audio starts, which is 0 if the key is missing.
+
+    // How many beats earlier than usual the countdown starts. Since this is in beats rather than milliseconds, and beat
+    // lengths are already scaled by `change_rate`, it stays the same at every rate.
+    pub countdown_offset: i32,
     rest: String,
 }
 
 impl GeneralInfo {
     fn into_string(self) -> String {
-        format!("[General]\nAudioFilename: {}\nPreviewTime: {}\n{}", self.audio_file, self.preview_time, self.rest)
+        let values = [
+            ("AudioFilename", self.audio_file),
+            ("PreviewTime", self.preview_time.to_string()),
+            ("AudioLeadIn", self.audio_lead_in.to_string()),
+            ("CountdownOffset", self.countdown_offset.to_string()),
+        ];
+        format!("[General]\n{}", substitute_values(&self.rest, ": ", &values))
     }
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct EditorInfo(String);
 
 impl EditorInfo {
@@ -103,19 +291,31 @@ impl EditorInfo {
     }
 }
 
+// Like `GeneralInfo`, `rest` holds every line of the section.
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Metadata {
     pub diff_name: String,
     rest: String,
 }
 
 impl Metadata {
+    // Returns the value of a key in the section (i.e. "Title"), if it's present. The value of "Version" is always
+    // `diff_name`, since the value in `rest` is only replaced with it on output.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        if key == "Version" {
+            return Some(&self.diff_name);
+        }
+        self.rest.lines().filter_map(|line| line.split_once(':')).find(|&(k, _)| k == key).map(|(_, value)| value)
+    }
+
     fn into_string(self) -> String {
-        format!("[Metadata]\nVersion:{}\n{}", self.diff_name, self.rest)
+        format!("[Metadata]\n{}", substitute_values(&self.rest, ":", &[("Version", self.diff_name)]))
     }
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DifficultyInfo(String);
 
 impl DifficultyInfo {
@@ -125,6 +325,7 @@ impl DifficultyInfo {
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Events(String);
 
 impl Events {
@@ -134,34 +335,100 @@ impl Events {
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct TimingPoint {
     // The spec on the wiki says `time` should be an integer, but some maps seem to violate that. `into_string` casts
     // this to an i32, since fractional millisecond differences are probably negligible.
     pub time: f64,
     pub beat_len: f64,
+    beat_len_text: String, // The original text of `beat_len`, kept so that re-saving doesn't change its precision.
     rest: String,
 }
 
 impl TimingPoint {
     fn into_string(self) -> String {
-        format!("{},{},{}", self.time as i32, self.beat_len, self.rest)
+        let beat_len = match self.beat_len_text.parse::<f64>() {
+            Ok(beat_len) if beat_len == self.beat_len => self.beat_len_text,
+            _ => format_osu_float(self.beat_len),
+        };
+        format!("{},{},{}", self.time as i32, beat_len, self.rest)
     }
 }
 
+// Colors are given as (red, green, blue). Like `GeneralInfo`, `rest` holds every line of the section (including
+// comments, and colors that couldn't be parsed), so that only the lines of colors that changed are rewritten.
 #[derive(Clone, Debug)]
-pub struct Colors(String);
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Colors {
+    pub combo_colors: Vec<(u8, u8, u8)>, // In the order they were given.
+    pub slider_track_override: Option<(u8, u8, u8)>,
+    pub slider_border: Option<(u8, u8, u8)>,
+    rest: String,
+}
 
 impl Colors {
+    // Combo colors replace the values of the combo color lines in `rest` in order, and lines left without a color are
+    // removed. Colors without a line are added after the last color, with combo colors numbered after every combo key
+    // in the section (even ones without a valid color), so that no key is repeated.
     fn into_string(self) -> String {
-        format!("[Colours]\n{}", self.0)
+        let mut combo_colors = self.combo_colors.into_iter();
+        let slider_colors = [("SliderTrackOverride", self.slider_track_override), ("SliderBorder", self.slider_border)];
+        let (mut lines, mut slider_colors_written, mut last_combo, mut new_colors_at) = (vec![], [false; 2], 0, 0);
+        for line in self.rest.lines() {
+            let combo = line.split_once(':').and_then(|(key, _)| parser::combo_number(key.trim()));
+            last_combo = last_combo.max(combo.unwrap_or(0));
+            let (key, old_color) = match parser::parse_color_line(line) {
+                Some(key_and_color) => key_and_color,
+                _ => {
+                    lines.push(line.to_string());
+                    continue;
+                }
+            };
+            let color = match slider_colors.iter().position(|&(k, _)| k == key) {
+                Some(i) => {
+                    slider_colors_written[i] = true;
+                    slider_colors[i].1
+                }
+                _ => combo_colors.next(),
+            };
+            if let Some(color) = color {
+                // The key is kept with the whitespace around it, as the value is only replaced if it changed.
+                let value = line.split_once(':').unwrap().1.trim_start();
+                let key_len = line.len() - value.len();
+                let changed = color != old_color;
+                lines.push(if changed { line[..key_len].to_string() + &rgb(color) } else { line.to_string() });
+                new_colors_at = lines.len();
+            }
+        }
+        if new_colors_at == 0 {
+            new_colors_at = lines.len();
+        }
+
+        let new_combo_colors = combo_colors.zip(last_combo + 1..).map(|(c, n)| (format!("Combo{}", n), c));
+        let new_slider_colors = slider_colors.iter().zip(&slider_colors_written).filter(|(_, &written)| !written);
+        let new_slider_colors = new_slider_colors.filter_map(|(&(key, c), _)| Some((key.to_string(), c?)));
+        let new_colors = new_combo_colors.chain(new_slider_colors).map(|(key, c)| format!("{} : {}", key, rgb(c)));
+        lines.splice(new_colors_at..new_colors_at, new_colors);
+        format!("[Colours]\n{}", lines.into_iter().map(|line| line + "\n").collect::<String>())
     }
 }
 
+// Formats a color as "r,g,b".
+fn rgb((r, g, b): (u8, u8, u8)) -> String {
+    format!("{},{},{}", r, g, b)
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct HitObject {
-    pub time: i32,
+    // Like `TimingPoint::time`, times are only cast to an i32 by `into_string`, so that precision isn't lost between
+    // transforms.
+    pub time: f64,
     pub params: HitObjectParams,
-    rest_parts: Vec<String>, // ["x,y", "type,hit_sound", "unused_object_params,hit_sample"]
+    // ["x,y", "type,hit_sound", ",unused_object_params,hit_sample"], where the last part keeps the commas before its
+    // fields, so that it's "" if there are none and "," if there's an empty one. Since the end time of a hold note is
+    // joined to its hit sample, only what follows the end time is stored for hold notes (i.e. ":0:0:0:0:", or "").
+    rest_parts: [String; 3],
 }
 
 impl HitObject {
@@ -169,7 +436,7 @@ impl HitObject {
         format!(
             "{},{},{}{}{}",
             self.rest_parts[0],
-            self.time,
+            self.time as i32,
             self.rest_parts[1],
             self.params.into_string(),
             self.rest_parts[2],
@@ -178,17 +445,473 @@ impl HitObject {
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum HitObjectParams {
     NoneUseful,
-    Spinner(i32),
-    LongNote(i32),
+    Spinner(f64),
+    LongNote(f64),
 }
 
 impl HitObjectParams {
     fn into_string(self) -> String {
         match self {
-            HitObjectParams::NoneUseful | HitObjectParams::LongNote(_) => ",".to_string(),
-            HitObjectParams::Spinner(end_time) => format!(",{},", end_time),
+            HitObjectParams::NoneUseful => String::new(),
+            HitObjectParams::Spinner(end_time) => format!(",{}", end_time as i32),
+            HitObjectParams::LongNote(end_time) => format!(",{}", end_time as i32),
         }
     }
 }
+
+// Changes a time in a beatmap from 1.0 to `rate`, compensating for the delay of the stretched audio.
+fn transform_time(time: f64, rate: f64, options: &RateOptions) -> f64 {
+    time / rate + audio_delay(rate) + options.offset
+}
+
+// Formats `n` the way osu! does, with at most 15 significant digits and no trailing zeros.
+fn format_osu_float(n: f64) -> String {
+    let int_digits = if n == 0. { 1 } else { n.abs().log10().floor() as i32 + 1 };
+    let formatted = format!("{:.*}", (15 - int_digits).max(0) as usize, n);
+    if formatted.contains('.') {
+        formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+        formatted
+    }
+}
+
+// Rewrites the `key{separator}value` lines of a section, replacing the values of the keys present in `values`.
+fn substitute_values(section: &str, separator: &str, values: &[(&str, String)]) -> String {
+    section
+        .lines()
+        .map(|line| match line.split_once(separator).and_then(|(key, _)| values.iter().find(|(k, _)| *k == key)) {
+            Some((key, value)) => format!("{}{}{}\n", key, separator, value),
+            _ => line.to_string() + "\n",
+        })
+        .collect()
+}
+
+// Parses the fixture named `name` in `tests/fixtures` (see `test_util::fixture`), for tests throughout the crate.
+#[cfg(test)]
+pub(crate) fn parse_fixture(name: &str) -> Beatmap {
+    Beatmap::parse(crate::test_util::fixture(name).as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beatmap::{parse_fixture, Beatmap, ChangeRateError, RateOptions};
+    use crate::test_util::fixture;
+
+    // The parser discards empty lines, and the output always uses LF line endings, so those differences are ignored
+    // when comparing.
+    fn normalize(map: &str) -> Vec<&str> {
+        map.lines().map(str::trim_end).filter(|l| !l.is_empty()).collect()
+    }
+
+    fn assert_round_trip(source: &str) {
+        let map = Beatmap::parse(source.as_bytes()).unwrap();
+        assert_eq!(normalize(&map.into_string()), normalize(source));
+    }
+
+    #[test]
+    fn round_trip_standard() {
+        assert_round_trip(&fixture("standard"));
+    }
+
+    #[test]
+    fn round_trip_mania() {
+        assert_round_trip(&fixture("mania"));
+    }
+
+    #[test]
+    fn round_trip_catch() {
+        assert_round_trip(&fixture("catch"));
+    }
+
+    #[test]
+    fn round_trip_taiko() {
+        assert_round_trip(&fixture("taiko"));
+    }
+
+    #[test]
+    fn round_trip_high_precision_beat_len() {
+        assert_round_trip(&fixture("high_precision"));
+    }
+
+    #[test]
+    fn metadata_values_are_found() {
+        let mut map = parse_fixture("standard");
+        assert_eq!((map.metadata.value("Title"), map.metadata.value("Artist")), (Some("Wanderflux"), Some("Frums")));
+        assert_eq!((map.metadata.value("Source"), map.metadata.value("Nonexistent")), (Some(""), None));
+
+        map.change_rate(1.2, &RateOptions::default()).unwrap();
+        assert_eq!(map.metadata.value("Version"), Some("Annihilation (1.2x)"));
+    }
+
+    #[test]
+    fn changed_colors_are_written() {
+        let mut map = parse_fixture("catch");
+        let colors = map.colors.as_mut().unwrap();
+        colors.combo_colors.remove(0);
+        colors.slider_border = Some((1, 2, 3));
+        let expected = "[Colours]\nCombo1 : 160,200,255\nSliderBorder : 1,2,3\n\n[HitObjects]";
+        assert!(map.into_string().contains(expected));
+    }
+
+    #[test]
+    fn colors_keep_their_lines_unless_changed() {
+        let source = fixture("standard").replace(
+            "Combo2 : 64,128,255\n",
+            "// combo colors\nCombo2 : 64,128,255\nCombo5 : 255,255\nSliderBorder: 1,2,3\nCustom: value\n",
+        );
+        let map = Beatmap::parse(source.as_bytes()).unwrap();
+        assert_eq!(normalize(&map.clone().into_string()), normalize(&source));
+
+        let mut map = map;
+        let colors = map.colors.as_mut().unwrap();
+        colors.combo_colors[1] = (0, 0, 0);
+        colors.combo_colors.push((9, 9, 9));
+        colors.slider_track_override = Some((4, 5, 6));
+        colors.slider_border = None;
+        let expected = "[Colours]\nCombo1 : 255,128,64\n// combo colors\nCombo2 : 0,0,0\nCombo5 : 255,255\n\
+            Custom: value\nCombo3 : 128,255,64\nCombo6 : 9,9,9\nSliderTrackOverride : 4,5,6\n\n[HitObjects]";
+        assert!(map.into_string().contains(expected));
+    }
+
+    #[test]
+    fn section_headers_tolerate_whitespace_and_byte_order_marks() {
+        let source = &fixture("standard");
+        let messy = source.replacen("osu file", "\u{feff}osu file", 1).replace("[Editor]", "[Editor]  \t")
+            .replace("[Metadata]", "\u{feff}[Metadata]");
+        let map = Beatmap::parse(messy.as_bytes()).unwrap();
+        assert_eq!(normalize(&map.into_string()), normalize(source));
+    }
+
+    #[test]
+    fn comments_are_kept_outside_of_timing_points_and_hit_objects() {
+        let source = fixture("standard")
+            .replace("[Editor]\n", "[Editor]\n//editor\n")
+            .replace("[General]\n", "[General]\n//general\n")
+            .replace("[HitObjects]\n", "[HitObjects]\n//hit objects\n");
+        let output = Beatmap::parse(source.as_bytes()).unwrap().into_string();
+        for comment in &["//editor", "//general", "//Break Periods", "//Storyboard Layer 0 (Background)"] {
+            assert!(output.contains(&format!("\n{}\n", comment)));
+        }
+        assert!(!output.contains("//hit objects"));
+    }
+
+    #[test]
+    fn changed_beat_len_uses_osu_precision() {
+        let mut map = parse_fixture("standard");
+        map.change_rate(1.125, &RateOptions::default()).unwrap();
+        assert!(map.into_string().contains("\n1141,333.333333333333,4,2,1,60,1,0\n"));
+    }
+
+    #[test]
+    fn timing_point_fields_are_kept_exactly() {
+        // Every field after the beat length (meter, sample set, sample index, volume, uninherited, and effects) is
+        // populated, and some points have trailing empty fields, which osu! ignores but shouldn't be dropped.
+        let source = fixture("standard").replace(
+            "1200,375,4,2,1,60,1,0\n1200,-100,4,2,1,60,0,0\n",
+            "1200,375,7,3,12,85,1,9,\n1200,-100,4,1,0,5,0,8,,\n",
+        );
+        assert_round_trip(&source);
+
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        map.change_rate(1.5, &RateOptions::default()).unwrap();
+        let output = map.into_string();
+        assert!(output.contains("\nSampleSet: Soft\n"));
+        assert!(output.contains("\n875,250,7,3,12,85,1,9,\n875,-100,4,1,0,5,0,8,,\n"));
+    }
+
+    #[test]
+    fn rate_of_one_leaves_timing_untouched() {
+        let source = &fixture("standard");
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        map.change_rate(1., &RateOptions::default()).unwrap();
+        assert_eq!(map.metadata.diff_name, "Annihilation (1x)");
+
+        let output = map.into_string().replace("Version:Annihilation (1x)", "Version:Annihilation");
+        assert_eq!(normalize(&output), normalize(source));
+    }
+
+    #[test]
+    fn hit_object_times_keep_precision_until_written() {
+        let mut map = parse_fixture("standard");
+        map.change_rate(1.3, &RateOptions::default()).unwrap();
+        assert_eq!(map.hit_objects[1].time, 1575. / 1.3 + 75.);
+        assert!(map.into_string().contains("\n320,192,1286,1,2,0:0:0:0:\n"));
+    }
+
+    #[test]
+    fn preview_time_can_be_scaled_exactly() {
+        let source = &fixture("standard");
+        for &(exact_preview_time, expected) in &[(false, 36171), (true, 36096)] {
+            let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+            map.change_rate(1.25, &RateOptions { exact_preview_time, ..Default::default() }).unwrap();
+            assert_eq!(map.general_info.preview_time, expected);
+        }
+
+        let mut map = Beatmap::parse(source.replace("PreviewTime: 45120", "PreviewTime: -1").as_bytes()).unwrap();
+        map.change_rate(1.25, &RateOptions { exact_preview_time: true, ..Default::default() }).unwrap();
+        assert_eq!(map.general_info.preview_time, -1);
+    }
+
+    #[test]
+    fn hold_notes_are_rated_with_any_hit_sample() {
+        let source = fixture("mania")
+            .replace("109,192,500,128,0,1333:0:0:0:0:", "109,192,500,128,0,1333")
+            .replace("402,192,8750,128,0,9250:0:0:0:0:", "402,192,8750,128,0,9250:0:0:0:0:a:b.wav");
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        map.change_rate(1.5, &RateOptions::default()).unwrap();
+
+        let output = map.into_string();
+        assert!(output.contains("\n109,192,408,128,0,963\n"));
+        assert!(output.contains("\n256,192,852,128,2,1408:1:0:0:0:hit.wav\n"));
+        assert!(output.ends_with("\n402,192,5908,128,0,6241:0:0:0:0:a:b.wav"));
+    }
+
+    #[test]
+    fn hit_objects_without_or_with_empty_hit_samples_are_kept() {
+        let source = fixture("standard")
+            .replace("256,192,1200,5,0,0:0:0:0:", "256,192,1200,5,0")
+            .replace("320,192,1575,1,2,0:0:0:0:", "320,192,1575,1,2,")
+            .replace("256,192,3450,12,4,7200,0:0:0:0:", "256,192,3450,12,4,7200");
+        assert_round_trip(&source);
+        let mania = fixture("mania")
+            .replace("109,192,500,128,0,1333:0:0:0:0:", "109,192,500,128,0,1333,");
+        assert_round_trip(&mania);
+
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        map.change_rate(1.5, &RateOptions::default()).unwrap();
+        let output = map.into_string();
+        assert!(output.contains("\n256,192,875,5,0\n320,192,1125,1,2,\n"));
+        assert!(output.contains("\n256,192,2375,12,4,4875\n"));
+    }
+
+    #[test]
+    fn videos_and_breaks_are_rated_with_the_map() {
+        let source = fixture("standard")
+            .replace("0,0,\"bg.jpg\",0,0\n", "0,0,\"bg.jpg\",0,0\nVideo,-1500,\"clip.mp4\"\n");
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        map.change_rate(1.5, &RateOptions::default()).unwrap();
+
+        let output = map.into_string();
+        assert!(output.contains("\n0,0,\"bg.jpg\",0,0\nVideo,-925,\"clip.mp4\"\n"));
+        assert!(output.contains("\n2,5408,7408\n"));
+    }
+
+    #[test]
+    fn rate_changes_can_be_applied_in_parts() {
+        let source = &fixture("standard");
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        map.apply_metadata(1.5, &RateOptions::default());
+        assert_eq!(map.metadata.diff_name, "Annihilation (1.5x)");
+        assert_eq!((map.timing_points[0].time, map.hit_objects[0].time), (1200., 1200.));
+
+        map.apply_timing(1.5, &RateOptions::default());
+        assert_eq!((map.timing_points[0].time, map.hit_objects[0].time), (875., 1200.));
+        map.apply_objects(1.5, &RateOptions::default()).unwrap();
+
+        let mut rated = Beatmap::parse(source.as_bytes()).unwrap();
+        rated.change_rate(1.5, &RateOptions::default()).unwrap();
+        assert_eq!(map.into_string(), rated.into_string());
+    }
+
+    #[test]
+    fn change_preview_time_leaves_everything_else_alone() {
+        let source = &fixture("standard");
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        map.change_preview_time(1.25, &RateOptions::default());
+        assert_eq!(map.general_info.preview_time, 36171);
+
+        let output = map.into_string().replace("PreviewTime: 36171", "PreviewTime: 45120");
+        assert_eq!(normalize(&output), normalize(source));
+    }
+
+    #[test]
+    fn length_includes_hold_note_end_times() {
+        let mut map = parse_fixture("mania");
+        let length = map.length();
+        assert_eq!(length, 9250.);
+
+        map.change_rate(1.25, &RateOptions::default()).unwrap();
+        assert_eq!(map.length(), length / 1.25 + 75.);
+    }
+
+    #[test]
+    fn bpms_come_from_uninherited_timing_points() {
+        let mut map = parse_fixture("high_precision");
+        let round = |bpm: f64| (bpm * 1000.).round() / 1000.;
+        assert_eq!(map.primary_bpm().map(round), Some(205.8));
+        assert_eq!(map.bpm_range().map(|(min, max)| (round(min), round(max))), Some((120., 205.8)));
+
+        map.change_rate(1.5, &RateOptions::default()).unwrap();
+        assert_eq!(map.primary_bpm().map(round), Some(308.7));
+        map.timing_points.clear();
+        assert_eq!((map.primary_bpm(), map.bpm_range()), (None, None));
+    }
+
+    #[test]
+    fn near_simultaneous_hit_objects_stay_sorted() {
+        let source = &fixture("standard");
+        let objects = (0..10).map(|i| format!("256,192,{},1,0,0:0:0:0:", 1000. + i as f64 * 0.3)).collect::<Vec<_>>();
+        let source = source[..source.find("[HitObjects]").unwrap()].to_string() + "[HitObjects]\n";
+        let source = source + &objects.join("\n");
+
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        map.change_rate(2.9, &RateOptions::default()).unwrap();
+        let output = map.into_string();
+        let times = output.lines().skip_while(|l| *l != "[HitObjects]").skip(1).map(|l| l.split(',').nth(2).unwrap());
+        let times = times.map(|t| t.parse::<i32>().unwrap()).collect::<Vec<_>>();
+        assert_eq!(times.len(), 10);
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn hit_objects_can_end_up_before_the_audio() {
+        let source = fixture("standard").replace("\n256,192,1200,", "\n256,192,-200,");
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        map.change_rate(2., &RateOptions::default()).unwrap();
+        assert_eq!(map.hit_objects_before_start(), 1);
+
+        // The delay of the stretched audio makes up for the difference at higher rates.
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        map.change_rate(4., &RateOptions::default()).unwrap();
+        assert_eq!(map.hit_objects_before_start(), 0);
+    }
+
+    #[test]
+    fn offset_is_added_to_every_changed_time() {
+        let mut map = parse_fixture("standard");
+        let mut offset_map = map.clone();
+        map.change_rate(1.2, &RateOptions::default()).unwrap();
+        offset_map.change_rate(1.2, &RateOptions { offset: -20., ..Default::default() }).unwrap();
+
+        assert_eq!(offset_map.general_info.preview_time, map.general_info.preview_time - 20);
+        assert_eq!(offset_map.timing_points[0].time, map.timing_points[0].time - 20.);
+        assert_eq!(offset_map.hit_objects[0].time, map.hit_objects[0].time - 20.);
+        assert_eq!(offset_map.length(), map.length() - 20.);
+    }
+
+    #[test]
+    fn offset_is_applied_at_normal_rate() {
+        let original = parse_fixture("standard");
+        let mut map = original.clone();
+        map.change_rate(1., &RateOptions { offset: -20., ..Default::default() }).unwrap();
+
+        assert_eq!(map.general_info.preview_time, original.general_info.preview_time - 20);
+        assert_eq!(map.timing_points[0].time, original.timing_points[0].time - 20.);
+        assert_eq!(map.timing_points[0].beat_len, original.timing_points[0].beat_len);
+        assert_eq!(map.hit_objects[0].time, original.hit_objects[0].time - 20.);
+        assert!(map.into_string().contains("\n2,7980,10980\n")); // The break in [Events].
+    }
+
+    #[test]
+    fn rate_suffix_is_detected() {
+        let mut map = parse_fixture("standard");
+        assert_eq!(map.rate_suffix(), None);
+        map.change_rate(1.25, &RateOptions::default()).unwrap();
+        assert_eq!(map.rate_suffix(), Some(1.25));
+
+        for &(name, rate) in &[("Insane (1x)", Some(1.)), ("Hard (x)", None), ("Box (inf x)", None), ("(1.2x)", None)] {
+            map.metadata.diff_name = name.to_string();
+            assert_eq!(map.rate_suffix(), rate, "{}", name);
+        }
+    }
+
+    #[test]
+    fn audio_lead_in_is_scaled() {
+        let source = fixture("standard").replace("AudioLeadIn: 0", "AudioLeadIn: 2000");
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        assert_eq!(map.general_info.audio_lead_in, 2000);
+
+        map.change_rate(1.5, &RateOptions { offset: 20., ..Default::default() }).unwrap();
+        assert_eq!(map.general_info.audio_lead_in, 1333);
+        assert!(map.into_string().contains("AudioLeadIn: 1333\n"));
+    }
+
+    #[test]
+    fn countdown_offset_is_kept() {
+        let source = fixture("standard")
+            .replace("Countdown: 0", "Countdown: 1\nCountdownOffset: 2");
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        assert_eq!(map.general_info.countdown_offset, 2);
+
+        map.change_rate(1.5, &RateOptions::default()).unwrap();
+        assert!(map.into_string().contains("Countdown: 1\nCountdownOffset: 2\n"));
+    }
+
+    #[test]
+    fn invalid_rates_fail_rate_change() {
+        let mut map = parse_fixture("standard");
+        for &rate in &[0., -1., f64::NAN, f64::INFINITY] {
+            assert!(matches!(map.change_rate(rate, &RateOptions::default()), Err(ChangeRateError::InvalidRate)));
+        }
+        assert_eq!(map.metadata.diff_name, "Annihilation");
+    }
+
+    #[test]
+    fn rates_overflowing_times_fail_rate_change() {
+        let mut map = parse_fixture("standard");
+        map.hit_objects.last_mut().unwrap().time = 30_000_000.;
+        let result = map.change_rate(0.01, &RateOptions::default());
+        assert!(matches!(result, Err(ChangeRateError::TimeOutOfRange)));
+        assert_eq!(map.metadata.diff_name, "Annihilation");
+        assert_eq!(map.hit_objects.last().unwrap().time, 30_000_000.);
+        map.change_rate(0.02, &RateOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn rates_overflowing_event_times_fail_rate_change() {
+        let source = fixture("standard").replace("2,8000,11000", "2,8000,30000000");
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        let result = map.change_rate(0.01, &RateOptions::default());
+        assert!(matches!(result, Err(ChangeRateError::TimeOutOfRange)));
+        assert_eq!(map.into_string(), Beatmap::parse(source.as_bytes()).unwrap().into_string());
+    }
+
+    #[test]
+    fn slider_velocities_out_of_range_are_counted() {
+        let source = &fixture("standard");
+        let mut map = Beatmap::parse(source.as_bytes()).unwrap();
+        assert_eq!(map.slider_velocities_out_of_range(), 0);
+
+        // These are 20x and 0.05x.
+        let mut inherited = map.timing_points.iter_mut().filter(|p| p.beat_len < 0.);
+        inherited.next().unwrap().beat_len = -5.;
+        inherited.next().unwrap().beat_len = -2000.;
+        assert_eq!(map.slider_velocities_out_of_range(), 2);
+        map.change_rate(1.5, &RateOptions::default()).unwrap();
+        assert_eq!(map.slider_velocities_out_of_range(), 2);
+    }
+
+    #[test]
+    fn inherited_timing_points_keep_their_parents() {
+        // Inherited points at the same time as, 1 ms after, and 1 ms before an uninherited point.
+        let source = fixture("standard").replace(
+            "12000,300,4,2,0,50,1,0",
+            "12000,300,4,2,0,50,1,0\n12000,-50,4,2,0,50,0,0\n12001,-200,4,2,0,50,0,0\n14999,-75,4,2,0,50,0,0\n\
+             15000,250,4,2,0,50,1,0\n15001,-90,4,2,0,50,0,0",
+        );
+        let map = Beatmap::parse(source.as_bytes()).unwrap();
+        let parents = map.timing_point_parents();
+        assert_eq!(&parents[2..], [Some(3), Some(3), Some(3), Some(7)]);
+
+        // At high rates, points 1 ms apart end up at the same time, which osu! resolves by their order in the file.
+        for &rate in &[0.5, 1.5, 3., 10.] {
+            let mut rated = map.clone();
+            rated.change_rate(rate, &RateOptions::default()).unwrap();
+            assert_eq!(rated.timing_point_parents(), parents, "{}", rate);
+
+            let times = rated.timing_points.iter().map(|p| p.time as i32).collect::<Vec<_>>();
+            assert!(times.windows(2).all(|pair| pair[0] <= pair[1]), "{}", rate);
+        }
+    }
+
+    #[test]
+    fn diff_name_can_be_kept() {
+        let mut map = parse_fixture("standard");
+        map.change_rate(1.2, &RateOptions { keep_diff_name: true, ..Default::default() }).unwrap();
+        assert_eq!(map.metadata.diff_name, "Annihilation");
+        assert_eq!(map.rate_suffix(), None);
+    }
+}
@@ -1,6 +1,6 @@
-use std::{io, result};
+use std::{error, fmt, io, result};
 use std::io::BufRead;
-use std::option::NoneError;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
 
 use crate::beatmap::{
@@ -14,23 +14,36 @@ use crate::util::verify;
 pub enum ParseError {
     UnsupportedVersion,
     InvalidBeatmap,
+    InvalidHoldNote,
+    NoTimingPoints,
     IoError,
 }
 
-impl From<io::Error> for ParseError {
-    fn from(_: io::Error) -> Self {
-        ParseError::IoError
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ParseError::UnsupportedVersion => "unsupported beatmap file format version",
+            ParseError::InvalidBeatmap => "couldn't parse beatmap file",
+            ParseError::InvalidHoldNote => "beatmap has a hold note without an end time",
+            ParseError::NoTimingPoints => "beatmap has no uninherited timing points",
+            ParseError::IoError => "beatmap file i/o error",
+        })
     }
 }
 
-impl From<NoneError> for ParseError {
-    fn from(_: NoneError) -> Self {
-        ParseError::InvalidBeatmap
+impl error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(_: io::Error) -> Self {
+        ParseError::IoError
     }
 }
 
 pub type Result<T> = result::Result<T, ParseError>;
 
+// Format versions whose section layout matches what the parser expects. The version is preserved on output.
+const SUPPORTED_VERSIONS: RangeInclusive<u32> = 12..=14;
+
 pub struct Parser<R: BufRead> {
     reader: R,
 }
@@ -41,9 +54,10 @@ impl<R: BufRead> Parser<R> {
     }
 
     pub fn parse(&mut self) -> Result<Beatmap> {
-        let header = trim_utf8_bom(self.read_line()?)?;
+        let header = self.read_line()?;
         verify_ff(header.starts_with("osu file format v"))?;
-        util::verify(&header[17..] == "14", ParseError::UnsupportedVersion)?;
+        let format_version = parse_ff(&header[17..])?;
+        util::verify(SUPPORTED_VERSIONS.contains(&format_version), ParseError::UnsupportedVersion)?;
 
         verify_ff(self.read_line()? == "[General]")?;
         let (general_info, next_section_header) = self.parse_general_info()?;
@@ -66,11 +80,14 @@ impl<R: BufRead> Parser<R> {
         verify_ff(next_section_header == "[TimingPoints]")?;
         let (timing_points, mut next_section_header) = self.parse_timing_points()?;
 
+        // osu! can't load a map without an uninherited timing point, which sets the first bpm.
+        verify(timing_points.iter().any(|p| p.beat_len.is_sign_positive()), ParseError::NoTimingPoints)?;
+
         // This section appears to be optional.
         let colors = if next_section_header == "[Colours]" {
-            let (rest, next) = self.read_section()?;
+            let (colors, next) = self.parse_colors()?;
             next_section_header = next;
-            Some(Colors(rest))
+            Some(colors)
         } else {
             None
         };
@@ -78,42 +95,61 @@ impl<R: BufRead> Parser<R> {
         verify_ff(next_section_header == "[HitObjects]")?;
         let hit_objects = self.parse_hit_objects()?;
 
-        Ok(Beatmap { general_info, editor_info, metadata, difficulty, events, timing_points, colors, hit_objects })
+        Ok(Beatmap {
+            format_version,
+            general_info,
+            editor_info,
+            metadata,
+            difficulty,
+            events,
+            timing_points,
+            colors,
+            hit_objects,
+        })
     }
 
     fn parse_general_info(&mut self) -> Result<(GeneralInfo, String)> {
         let mut audio_file = String::new();
         let mut preview_time = -1;
+        let mut audio_lead_in = 0;
+        let mut countdown_offset = 0;
         let mut rest = String::new();
 
-        let mut line = self.read_line()?;
+        let mut line = self.read_line_keeping_comments()?;
         while !is_section_header_or_eof(&line) {
-            let (key, value) = line.split_once(": ")?;
-            match key {
-                "AudioFilename" => audio_file = value.to_string(),
-                "PreviewTime" => preview_time = parse_ff(value)?,
-                _ => rest += &(line + "\n"),
+            if !is_comment(&line) {
+                let (key, value) = line.split_once(": ").ok_or(ParseError::InvalidBeatmap)?;
+                match key {
+                    "AudioFilename" => audio_file = value.to_string(),
+                    "PreviewTime" => preview_time = parse_ff(value)?,
+                    "AudioLeadIn" => audio_lead_in = parse_ff(value)?,
+                    "CountdownOffset" => countdown_offset = parse_ff(value)?,
+                    _ => {}
+                }
             }
-            line = self.read_line()?;
+            rest += &(line + "\n");
+            line = self.read_line_keeping_comments()?;
         }
 
         // Verify that required values were parsed.
         verify_ff(!audio_file.is_empty())?;
-        Ok((GeneralInfo { audio_file, preview_time, rest }, line))
+        Ok((GeneralInfo { audio_file, preview_time, audio_lead_in, countdown_offset, rest }, line))
     }
 
     fn parse_metadata(&mut self) -> Result<(Metadata, String)> {
         let mut diff_name = String::new();
         let mut rest = String::new();
 
-        let mut line = self.read_line()?;
+        let mut line = self.read_line_keeping_comments()?;
         while !is_section_header_or_eof(&line) {
-            let (key, value) = line.split_once(":")?;
-            match key {
-                "Version" => diff_name = value.to_string(),
-                _ => rest += &(line + "\n"),
+            if !is_comment(&line) {
+                let (key, value) = line.split_once(":").ok_or(ParseError::InvalidBeatmap)?;
+                if key == "Version" {
+                    diff_name = value.to_string();
+                }
             }
-            line = self.read_line()?;
+            rest += &(line + "\n");
+            line = self.read_line_keeping_comments()?;
         }
 
         // Verify that required values were parsed.
@@ -121,6 +157,28 @@ impl<R: BufRead> Parser<R> {
         Ok((Metadata { diff_name, rest }, line))
     }
 
+    // Lines that aren't colors osurate knows about (or that don't hold a valid color) are kept as they are, instead of
+    // being rejected, since they don't matter for changing the rate.
+    fn parse_colors(&mut self) -> Result<(Colors, String)> {
+        let mut combo_colors = vec![];
+        let mut slider_track_override = None;
+        let mut slider_border = None;
+        let mut rest = String::new();
+
+        let mut line = self.read_line_keeping_comments()?;
+        while !is_section_header_or_eof(&line) {
+            match parse_color_line(&line) {
+                Some(("SliderTrackOverride", color)) => slider_track_override = Some(color),
+                Some(("SliderBorder", color)) => slider_border = Some(color),
+                Some((_, color)) => combo_colors.push(color),
+                _ => {}
+            }
+            rest += &(line + "\n");
+            line = self.read_line_keeping_comments()?;
+        }
+        Ok((Colors { combo_colors, slider_track_override, slider_border, rest }, line))
+    }
+
     fn parse_timing_points(&mut self) -> Result<(Vec<TimingPoint>, String)> {
         let mut timing_points = vec![];
 
@@ -131,7 +189,8 @@ impl<R: BufRead> Parser<R> {
 
             let time = parse_ff(split[0])?;
             let beat_len = parse_ff(split[1])?;
-            timing_points.push(TimingPoint { time, beat_len, rest: split[2].to_string() });
+            let (beat_len_text, rest) = (split[1].to_string(), split[2].to_string());
+            timing_points.push(TimingPoint { time, beat_len, beat_len_text, rest });
             line = self.read_line()?;
         }
         Ok((timing_points, line))
@@ -143,24 +202,34 @@ impl<R: BufRead> Parser<R> {
         let mut line = self.read_line()?;
         while !is_section_header_or_eof(&line) {
             let mut split = line.split(',');
-            let mut rest_parts = vec![]; // See `beatmap/mod.rs`.
 
-            rest_parts.push(format!("{},{}", split.next()?, split.next()?));
-            let time = parse_ff(split.next()?)?;
-            let kind = parse_ff::<i32>(split.next()?)?;
-            rest_parts.push(format!("{},{}", kind, split.next()?));
+            let x = split.next().ok_or(ParseError::InvalidBeatmap)?;
+            let y = split.next().ok_or(ParseError::InvalidBeatmap)?;
+            let position = format!("{},{}", x, y);
+            let time = parse_ff(split.next().ok_or(ParseError::InvalidBeatmap)?)?;
+            let kind = parse_ff::<i32>(split.next().ok_or(ParseError::InvalidBeatmap)?)?;
+            let kind_and_hit_sound = format!("{},{}", kind, split.next().ok_or(ParseError::InvalidBeatmap)?);
 
+            let mut rest = String::new();
             let params = if kind & (1 << 0) == 1 || kind & (1 << 1) == 2 {
                 HitObjectParams::NoneUseful
             } else if kind & (1 << 3) == 8 {
-                HitObjectParams::Spinner(parse_ff(split.next()?)?)
+                HitObjectParams::Spinner(parse_ff(split.next().ok_or(ParseError::InvalidBeatmap)?)?)
             } else if kind & (1 << 7) == 128 {
-                let end_time = split.clone().next()?.split_once(':')?.0;
-                HitObjectParams::LongNote(parse_ff(end_time)?)
+                // The end time of a hold note is joined to its hit sample by a colon (i.e. "1500:0:0:0:0:"), but older
+                // maps may leave out the hit sample entirely (i.e. "1500"). Only the hit sample is kept in `rest`.
+                let field = split.next().ok_or(ParseError::InvalidHoldNote)?;
+                let end_time_len = field.find(':').unwrap_or(field.len());
+                rest += &field[end_time_len..];
+                HitObjectParams::LongNote(field[..end_time_len].parse().or(Err(ParseError::InvalidHoldNote))?)
             } else {
                 return Err(ParseError::InvalidBeatmap);
             };
-            rest_parts.push(split.collect::<Vec<_>>().join(","));
+
+            // See `beatmap/mod.rs`. Each remaining field is kept with the comma before it, so that lines without a hit
+            // sample (i.e. "256,192,1000,1,0") and lines with an empty one (i.e. "256,192,1000,1,0,") both stay as-is.
+            rest.extend(split.map(|field| format!(",{}", field)));
+            let rest_parts = [position, kind_and_hit_sound, rest];
 
             hit_objects.push(HitObject { time, params, rest_parts });
             line = self.read_line()?;
@@ -171,20 +240,33 @@ impl<R: BufRead> Parser<R> {
         Ok(hit_objects)
     }
 
-    // Read an entire section to a string without any special parsing.
+    // Read an entire section to a string without any special parsing. Comments are kept, since they may be meaningful
+    // (i.e. the layer markers in [Events]).
     fn read_section(&mut self) -> io::Result<(String, String)> {
         let mut rest = String::new();
 
-        let mut line = self.read_line()?;
+        let mut line = self.read_line_keeping_comments()?;
         while !is_section_header_or_eof(&line) {
             rest += &(line + "\n");
-            line = self.read_line()?;
+            line = self.read_line_keeping_comments()?;
         };
         Ok((rest, line))
     }
 
-    // Reads a line from `reader`, discarding the newline delimiter and skipping empty lines and comments.
+    // Reads a line from `reader`, discarding the newline delimiter, trailing whitespace, and any byte order mark, and
+    // skipping empty lines and comments. Some tools leave byte order marks in the middle of the file, not just at the
+    // start.
     fn read_line(&mut self) -> io::Result<String> {
+        let line = self.read_line_keeping_comments()?;
+        if is_comment(&line) {
+            self.read_line()
+        } else {
+            Ok(line)
+        }
+    }
+
+    // Like `read_line`, but only skips empty lines.
+    fn read_line_keeping_comments(&mut self) -> io::Result<String> {
         let mut buf = String::new();
 
         // Return an empty string on EOF.
@@ -192,11 +274,11 @@ impl<R: BufRead> Parser<R> {
             return Ok(buf);
         }
 
-        // Skip empty lines and comments.
-        if buf.trim().is_empty() || buf.starts_with("//") {
-            self.read_line()
+        let line = buf.trim_start_matches('\u{feff}').trim_end();
+        if line.is_empty() {
+            self.read_line_keeping_comments()
         } else {
-            Ok(buf.trim_end().to_string())
+            Ok(line.to_string())
         }
     }
 }
@@ -211,16 +293,109 @@ fn parse_ff<F: FromStr>(str: &str) -> Result<F> {
     str.parse().or(Err(ParseError::InvalidBeatmap))
 }
 
-// Checks if `line` is a section header (i.e. "[Metadata]") or was the result of reaching EOF.
+// Splits a line of the [Colours] section into its key and color, if it holds a valid color that osurate knows about
+// (a combo color, "SliderTrackOverride", or "SliderBorder").
+pub(super) fn parse_color_line(line: &str) -> Option<(&str, (u8, u8, u8))> {
+    let (key, value) = line.split_once(':')?;
+    let key = key.trim();
+    let known = combo_number(key).is_some() || key == "SliderTrackOverride" || key == "SliderBorder";
+    Some((key, parse_color(value).filter(|_| known)?))
+}
+
+// Returns the number of a combo color's key (i.e. 2 for "Combo2"), if it is one.
+pub(super) fn combo_number(key: &str) -> Option<u32> {
+    key.strip_prefix("Combo")?.parse().ok()
+}
+
+// Parses a color given as "r,g,b", where each component is from 0 to 255.
+fn parse_color(value: &str) -> Option<(u8, u8, u8)> {
+    let mut components = value.split(',').map(|c| c.trim().parse::<u8>().ok());
+    match (components.next()?, components.next()?, components.next()?, components.next()) {
+        (Some(r), Some(g), Some(b), None) => Some((r, g, b)),
+        _ => None,
+    }
+}
+
+fn is_comment(line: &str) -> bool {
+    line.starts_with("//")
+}
+
+// Checks if `line` is a section header (i.e. "[Metadata]") or was the result of reaching EOF. Section names never
+// contain commas, unlike data lines which may happen to be bracketed.
 fn is_section_header_or_eof(line: &str) -> bool {
-    line.chars().next() == Some('[') && line.chars().last() == Some(']') || line.is_empty()
+    (line.starts_with('[') && line.ends_with(']') && !line.contains(',')) || line.is_empty()
 }
 
-// Trims the byte order mark from the start of a UTF-8 string, if present.
-fn trim_utf8_bom(line: String) -> Option<String> {
-    if line.as_bytes().starts_with(b"\xef\xbb\xbf") {
-        String::from_utf8(line.as_bytes()[3..].to_vec()).ok()
-    } else {
-        Some(line.to_string())
+#[cfg(test)]
+mod tests {
+    use crate::beatmap::{Beatmap, HitObjectParams};
+    use crate::beatmap::parser::{is_section_header_or_eof, ParseError};
+    use crate::test_util::fixture;
+
+    #[test]
+    fn section_headers_are_detected() {
+        assert!(is_section_header_or_eof("[General]"));
+        assert!(is_section_header_or_eof("[]"));
+        assert!(is_section_header_or_eof(""));
+    }
+
+    #[test]
+    fn bracketed_data_is_not_a_section_header() {
+        assert!(!is_section_header_or_eof("[1,2,3]"));
+        assert!(!is_section_header_or_eof("[General"));
+        assert!(!is_section_header_or_eof("General]"));
+        assert!(!is_section_header_or_eof("["));
+        assert!(!is_section_header_or_eof("Version:[Annihilation]"));
+    }
+
+    #[test]
+    fn truncated_hold_notes_are_rejected() {
+        let source = &fixture("mania");
+        for truncated in &["109,192,500,128,0,", "109,192,500,128,0", "109,192,500,128,0,:", "109,192,500,128,0,a:0"] {
+            let source = source.replace("109,192,500,128,0,1333:0:0:0:0:", truncated);
+            assert!(matches!(Beatmap::parse(source.as_bytes()), Err(ParseError::InvalidHoldNote)), "{}", truncated);
+        }
+    }
+
+    #[test]
+    fn hold_notes_with_any_hit_sample_are_parsed() {
+        let source = &fixture("mania");
+        let hold_notes = [
+            "109,192,500,128,0,1333", // Without a hit sample.
+            "109,192,500,128,0,1333:0:0:0:0:a:b.wav", // With extra colons.
+            "109,192,500,128,0,1333,", // With an empty extra field.
+        ];
+        for &hold_note in &hold_notes {
+            let map = Beatmap::parse(source.replace("109,192,500,128,0,1333:0:0:0:0:", hold_note).as_bytes()).unwrap();
+            assert!(matches!(map.hit_objects[1].params, HitObjectParams::LongNote(end_time) if end_time == 1333.));
+            assert!(map.into_string().contains(&format!("\n{}\n", hold_note)), "{}", hold_note);
+        }
+    }
+
+    #[test]
+    fn maps_without_uninherited_timing_points_are_rejected() {
+        let source = &fixture("standard");
+        let timing_points = source.split("[TimingPoints]").nth(1).unwrap().split("\n\n").next().unwrap();
+        let inherited = timing_points.lines().filter(|l| l.contains(",-")).map(|l| format!("\n{}", l));
+        let inherited = inherited.collect::<String>();
+        for replacement in &["\n", inherited.as_str()] {
+            let source = source.replace(timing_points, replacement);
+            assert!(matches!(Beatmap::parse(source.as_bytes()), Err(ParseError::NoTimingPoints)), "{}", replacement);
+        }
+    }
+
+    #[test]
+    fn colors_are_parsed() {
+        let source = &fixture("standard");
+        let map = Beatmap::parse(source.as_bytes()).unwrap();
+        let colors = map.colors.unwrap();
+        assert_eq!(colors.combo_colors, [(255, 128, 64), (64, 128, 255), (128, 255, 64)]);
+        assert_eq!((colors.slider_track_override, colors.slider_border), (None, None));
+
+        let source = source.replace("Combo3 : 128,255,64", "Combo3:1, 2 ,3\nSliderBorder : 0,0,0\nCombo4 : 256,0,0");
+        let colors = Beatmap::parse(source.as_bytes()).unwrap().colors.unwrap();
+        assert_eq!(colors.combo_colors, [(255, 128, 64), (64, 128, 255), (1, 2, 3)]);
+        assert_eq!(colors.slider_border, Some((0, 0, 0)));
+        assert!(colors.rest.ends_with("\nCombo4 : 256,0,0\n")); // Out of range, so it's only kept as is.
     }
 }
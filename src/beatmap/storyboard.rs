@@ -0,0 +1,192 @@
+use std::result;
+
+use crate::beatmap::{format_osu_float, transform_time, ChangeRateError, RateOptions};
+use crate::util;
+
+// Changes the rate of a storyboard (i.e. the contents of an .osb file) from 1.0 to `rate`, using the same transform as
+// `Beatmap::change_rate`, so that it stays in sync with the rated map. Like with beatmaps, only `options.offset` is
+// applied at 1.0x, and nothing changes at all without it.
+//
+// Only the [Events] section is changed. Absolute times (of objects and of top level commands) are transformed like hit
+// object times, while durations (the frame delays of animations, and the times of commands in loops and triggers,
+// which are relative to the start of the loop or trigger) are only scaled by the rate. Since variables may stand for
+// any part of a line (including times), they are expanded. Everything else, including comments and line endings, is
+// kept as-is. Like with beatmaps, times that would overflow the 32-bit integers they're saved as are an error.
+pub fn change_rate(storyboard: &str, rate: f64, options: &RateOptions) -> result::Result<String, ChangeRateError> {
+    change_sections(storyboard, "", rate, options)
+}
+
+// Changes the rate of the [Events] section of a beatmap (without its header), which is written like that of a
+// storyboard, so that its videos, breaks, and storyboard stay in sync too. Backgrounds have no time, so they're kept.
+pub(crate) fn change_events_rate(
+    events: &str,
+    rate: f64,
+    options: &RateOptions,
+) -> result::Result<String, ChangeRateError> {
+    change_sections(events, "[Events]", rate, options)
+}
+
+// Like `change_rate`, starting in `first_section`.
+fn change_sections(
+    storyboard: &str,
+    first_section: &str,
+    rate: f64,
+    options: &RateOptions,
+) -> result::Result<String, ChangeRateError> {
+    if rate == 1. && options.offset == 0. {
+        return Ok(storyboard.to_string());
+    }
+    let time = |t: f64| to_saved_time(transform_time(t, rate, options));
+    let duration = |d: f64| to_saved_time(d / rate);
+    let variables = parse_variables(storyboard);
+
+    let mut section = first_section;
+    let mut in_compound = false; // Whether the last top level command was a loop or trigger.
+    let lines = storyboard.split_inclusive('\n').map(|line| {
+        let content = line.trim_end_matches(&['\r', '\n'][..]);
+        let ending = &line[content.len()..];
+        if content.starts_with('[') {
+            section = content.trim_end();
+        }
+        if section != "[Events]" || content.starts_with('[') || content.starts_with("//") {
+            return Ok(line.to_string());
+        }
+
+        let content = expand_variables(content, &variables);
+        let command = content.trim_start_matches(&[' ', '_'][..]);
+        let indent = &content[..content.len() - command.len()];
+        let mut fields = command.split(',').map(str::to_string).collect::<Vec<_>>();
+        match (indent.len(), fields[0].as_str()) {
+            (0, "Sample") | (0, "5") | (0, "Video") | (0, "1") => change_field(&mut fields, 1, time)?,
+            (0, "Animation") | (0, "6") => {
+                let frame_delay = fields.get(7).and_then(|d| d.trim().parse::<f64>().ok());
+                if let Some(frame_delay) = frame_delay {
+                    fields[7] = format_osu_float(frame_delay / rate);
+                }
+            }
+            (0, "Break") | (0, "2") => {
+                change_field(&mut fields, 1, time)?;
+                change_field(&mut fields, 2, time)?;
+            }
+            (0, _) => {}
+            // Loops only have a start time, while triggers and other commands have a start and end time (where an empty
+            // end time means that it's the same as the start time).
+            (1, "L") => change_field(&mut fields, 1, time)?,
+            (1, _) => {
+                change_field(&mut fields, 2, time)?;
+                change_field(&mut fields, 3, time)?;
+            }
+            _ if in_compound => {
+                change_field(&mut fields, 2, duration)?;
+                change_field(&mut fields, 3, duration)?;
+            }
+            _ => {}
+        }
+        if indent.len() == 1 {
+            in_compound = fields[0] == "L" || fields[0] == "T";
+        } else if indent.is_empty() {
+            in_compound = false;
+        }
+        Ok(format!("{}{}{}", indent, fields.join(","), ending))
+    });
+    lines.collect()
+}
+
+// Replaces the field at `index` with `change` applied to it, if it's there and is a number.
+fn change_field(
+    fields: &mut [String],
+    index: usize,
+    change: impl Fn(f64) -> result::Result<i32, ChangeRateError>,
+) -> result::Result<(), ChangeRateError> {
+    if let Some(field) = fields.get_mut(index) {
+        if let Ok(value) = field.trim().parse::<f64>() {
+            *field = change(value)?.to_string();
+        }
+    }
+    Ok(())
+}
+
+// Converts a changed time (or duration) to the integer it's saved as, failing instead of saturating if it's too large.
+fn to_saved_time(time: f64) -> result::Result<i32, ChangeRateError> {
+    util::verify((i32::MIN as f64..=i32::MAX as f64).contains(&time), ChangeRateError::TimeOutOfRange)?;
+    Ok(time as i32)
+}
+
+// Returns the variables defined in the [Variables] section (i.e. "$red=255,0,0"), longest names first, so that no
+// variable is mistaken for a longer one starting with its name when they're expanded.
+fn parse_variables(storyboard: &str) -> Vec<(&str, &str)> {
+    let mut section = "";
+    let mut variables = vec![];
+    for line in storyboard.lines().map(str::trim_end) {
+        if line.starts_with('[') {
+            section = line;
+        } else if section == "[Variables]" && line.starts_with('$') {
+            variables.extend(line.split_once('='));
+        }
+    }
+    variables.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+    variables
+}
+
+fn expand_variables(line: &str, variables: &[(&str, &str)]) -> String {
+    if !line.contains('$') {
+        return line.to_string();
+    }
+    variables.iter().fold(line.to_string(), |line, (name, value)| line.replace(name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beatmap::{storyboard, ChangeRateError, RateOptions};
+
+    #[test]
+    fn storyboard_times_are_changed() {
+        let source = [
+            "[Variables]",
+            "$start=1000",
+            "$s=Sprite",
+            "[Events]",
+            "//Storyboard Layer 0 (Background)",
+            "$s,Background,Centre,\"bg.png\",320,240",
+            " F,0,$start,2000,0,1",
+            " M,0,3000,,320,240",
+            " L,4000,3",
+            "  R,0,0,500,0,3.14",
+            " T,HitSoundClap,5000,6000",
+            "  S,0,0,100,1,2",
+            " F,0,7000,8000,1,0",
+            "Animation,Foreground,Centre,\"frame.png\",0,0,4,100,LoopForever",
+            "Sample,9000,0,\"clap.wav\",100",
+        ];
+        let expected = [
+            "[Variables]",
+            "$start=1000",
+            "$s=Sprite",
+            "[Events]",
+            "//Storyboard Layer 0 (Background)",
+            "Sprite,Background,Centre,\"bg.png\",320,240",
+            " F,0,575,1075,0,1",
+            " M,0,1575,,320,240",
+            " L,2075,3",
+            "  R,0,0,250,0,3.14",
+            " T,HitSoundClap,2575,3075",
+            "  S,0,0,50,1,2",
+            " F,0,3575,4075,1,0",
+            "Animation,Foreground,Centre,\"frame.png\",0,0,4,50,LoopForever",
+            "Sample,4575,0,\"clap.wav\",100",
+        ];
+
+        let source = source.join("\r\n") + "\r\n";
+        let rated = storyboard::change_rate(&source, 2., &RateOptions::default()).unwrap();
+        assert_eq!(rated, expected.join("\r\n") + "\r\n");
+        assert_eq!(storyboard::change_rate(&source, 1., &RateOptions::default()).unwrap(), source);
+    }
+
+    #[test]
+    fn storyboard_times_out_of_range_fail_rate_change() {
+        let source = "[Events]\nSample,30000000,0,\"clap.wav\",100\n";
+        let result = storyboard::change_rate(source, 0.01, &RateOptions::default());
+        assert!(matches!(result, Err(ChangeRateError::TimeOutOfRange)));
+        assert!(storyboard::change_rate(source, 0.02, &RateOptions::default()).is_ok());
+    }
+}
@@ -0,0 +1,981 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use filetime::FileTime;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use rayon::prelude::*;
+
+use crate::audio;
+use crate::beatmap::{storyboard, Beatmap, RateOptions};
+use crate::util;
+
+// Set to cancel generation (i.e. by the CLI when Ctrl-C is pressed), after which `generate_rates` fails with
+// `EXIT_CANCELLED` for the maps and rates that haven't been started yet.
+pub static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+fn check_cancelled() -> Result<(), Error> {
+    util::verify(!CANCELLED.load(Ordering::SeqCst), Error::new(EXIT_CANCELLED, "cancelled"))
+}
+
+// The exit codes of the CLI, which let scripts tell what kind of error stopped it. Other errors (i.e. invalid
+// arguments) exit with `EXIT_FAILURE`.
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_BEATMAP: i32 = 2; // A beatmap couldn't be found, read, or parsed.
+pub const EXIT_AUDIO_SOURCE: i32 = 3; // The audio to stretch couldn't be found, read, or decoded.
+pub const EXIT_ENCODER: i32 = 4; // The stretched audio couldn't be encoded, by LAME or by ffmpeg.
+pub const EXIT_CANCELLED: i32 = 130; // Generation was cancelled with Ctrl-C (see `CANCELLED`).
+
+// An error from generating rates, along with the exit code that the CLI reports it with.
+#[derive(Clone, Debug)]
+pub struct Error {
+    pub message: String,
+    pub exit_code: i32,
+}
+
+impl Error {
+    pub fn new(exit_code: i32, message: impl Into<String>) -> Self {
+        Error { message: message.into(), exit_code }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+// Most errors are only described by their message, so they don't have a more specific exit code.
+impl From<String> for Error {
+    fn from(message: String) -> Self {
+        Error::new(EXIT_FAILURE, message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Self {
+        Error::new(EXIT_FAILURE, message)
+    }
+}
+
+impl From<audio::AudioStretchError> for Error {
+    fn from(e: audio::AudioStretchError) -> Self {
+        let exit_code = match e {
+            audio::AudioStretchError::SourceNotFound
+            | audio::AudioStretchError::InvalidSource
+            | audio::AudioStretchError::UnsupportedChannelCount => EXIT_AUDIO_SOURCE,
+            audio::AudioStretchError::FfmpegError
+            | audio::AudioStretchError::LameInitializationError
+            | audio::AudioStretchError::LameConfigurationError
+            | audio::AudioStretchError::LameEncodingError => EXIT_ENCODER,
+            _ => EXIT_FAILURE,
+        };
+        Error::new(exit_code, e.to_string())
+    }
+}
+
+// The lowest rate allowed unless `--min-rate` is used. Lower rates work, but stretching audio by them takes a lot of
+// time, memory, and disk space, so they're more likely to be typos than intentional.
+pub const MIN_RATE: f64 = 0.01;
+
+// Returns the stretcher to use for audio, which is `native` unless `ffmpeg` is set. ffmpeg falls back to `native` if it
+// isn't installed.
+pub fn audio_stretcher(ffmpeg: bool, native: audio::Mp3Stretcher) -> Box<dyn audio::AudioStretcher> {
+    #[cfg(feature = "ffmpeg")]
+    if ffmpeg {
+        return Box::new(audio::FfmpegStretcher { executable: None, fallback: native });
+    }
+    #[cfg(not(feature = "ffmpeg"))]
+    let _ = ffmpeg;
+    Box::new(native)
+}
+
+// The rates to generate for each map, either given directly or derived from the BPM of each map.
+pub enum Rates {
+    Fixed(Vec<f64>),
+    TargetBpm(f64),
+}
+
+// Settings that apply to the generation of every rate.
+#[derive(Default)]
+pub struct Options {
+    pub no_audio: bool, // If set, `AudioFilename` is left as-is instead of pointing to newly stretched audio.
+    pub rate_folders: bool, // If set, each rate is saved in a "rate_{rate}" subdirectory of the output directory.
+    pub filename_format: Option<String>, // Overrides `rated_file_name` if set (see `format_file_name`).
+    pub output_dir: Option<PathBuf>, // Where rates are saved if set, instead of next to the original map.
+    pub min_rate: f64, // The lowest rate `Rates::TargetBpm` can result in (see `MIN_RATE`).
+    pub threads: Option<usize>, // The number of threads used to resample audio, or one per core if `None`.
+    pub jobs: usize, // If more than 1, the rates of a map are generated in parallel on the current rayon thread pool.
+    pub pitch: f64, // The number of semitones stretched audio is pitch shifted by, on top of the rate.
+    pub loudness_target: Option<f64>, // The integrated loudness in LUFS that stretched audio is matched to, if set.
+    pub anti_alias: bool, // If set, audio is low-pass filtered before being sped up (see `audio::stretch`).
+    pub ffmpeg: bool, // If set, audio is stretched with ffmpeg (see `audio_stretcher`).
+    pub preview_only: bool, // If set, only the preview time is changed, and the original map is overwritten.
+    pub preserve_mtime: bool, // If set, generated files keep the modification time of the files they came from.
+    pub rate_options: RateOptions,
+}
+
+// The files created for a single rate of a map.
+pub struct GeneratedRate {
+    pub rate: f64,
+    pub length: f64, // See `Beatmap::length`.
+    pub map_path: PathBuf,
+    pub audio_path: Option<PathBuf>, // This is `None` if no audio was created (i.e. at 1.0x or with `no_audio`).
+    pub storyboard_path: Option<PathBuf>, // This is `None` if the map has no .osb storyboard, or it wasn't rated.
+}
+
+impl GeneratedRate {
+    pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.map_path).chain(&self.audio_path).chain(&self.storyboard_path)
+    }
+
+    // Lists the names of the created files, used for user-facing logging.
+    pub fn file_names(&self) -> String {
+        let names = self.paths().filter_map(|p| p.file_name()).map(|n| n.to_string_lossy());
+        names.collect::<Vec<_>>().join(", ")
+    }
+}
+
+// A map that couldn't be generated at all, or a single rate of it (if `rate` is set), reported at the end by the CLI.
+pub struct Failure<'a> {
+    pub path: &'a Path,
+    pub rate: Option<f64>,
+    pub error: Error,
+}
+
+impl fmt::Display for Failure<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rate {
+            Some(rate) => write!(f, "couldn't generate {}x rate of {}: {}", rate, self.path.display(), self.error),
+            _ => write!(f, "{}: {}", self.path.display(), self.error),
+        }
+    }
+}
+
+// Progress made while generating the rates of a map, which is reported to the `progress` callback of `generate_rates`.
+// Since rates may be generated in parallel (see `Options::jobs`), events from different rates can be interleaved.
+pub enum Progress<'a> {
+    ParsedMap { map_name: &'a str, n_rates: usize },
+    StretchedAudio { map_name: &'a str, rate: f64 }, // This isn't reported if no audio was stretched.
+    WroteMap { map_name: &'a str, generated: &'a GeneratedRate },
+    RateFailed { map_name: &'a str, rate: f64, error: &'a Error }, // See `generate_rates`.
+}
+
+// Generates and saves the rates in `rates` for the .osu file at `path`, returning the files created for each rate that
+// was generated. Progress is reported to `progress` as it is made. Nothing more is generated once `CANCELLED` is set.
+pub fn generate_rates(
+    path: &Path,
+    rates: &Rates,
+    options: &Options,
+    progress: &(dyn Fn(Progress) + Sync),
+) -> Result<Vec<GeneratedRate>, Error> {
+    generate_batch(&[path], rates, options, &|_, p| progress(p)).pop().unwrap()
+}
+
+// Like `generate_rates`, for each of the maps at `paths`, returning the result for each map in the same order. Progress
+// is reported to `progress` along with the path of the map it was made on. The difficulties of a set usually share the
+// same audio, which is only stretched (and saved) once for each rate, and then used by every difficulty that needs it,
+// so that the same file is never written to twice at the same time. With `Options::jobs`, maps (and the audio they use)
+// are generated in parallel too.
+pub fn generate_batch<'a>(
+    paths: &[&'a Path],
+    rates: &Rates,
+    options: &Options,
+    progress: &(dyn Fn(&'a Path, Progress) + Sync),
+) -> Vec<Result<Vec<GeneratedRate>, Error>> {
+    // Everything but the audio is done in memory first, so that maps and rates that can't be generated (i.e. ones that
+    // would make a map too long) fail right away, instead of after all of the work on the audio.
+    let prepare = |&path: &&'a Path| prepare_map(path, rates, options, &|p| progress(path, p));
+    let maps = if options.jobs > 1 {
+        paths.par_iter().map(prepare).collect::<Vec<_>>()
+    } else {
+        paths.iter().map(prepare).collect()
+    };
+    let prepared = paths.iter().zip(&maps).filter_map(|(&path, map)| Some((path, map.as_ref().ok()?)));
+    let audio = stretch_audio(&prepared.collect::<Vec<_>>(), options, progress);
+
+    let save = |(&path, map): (&&'a Path, Result<PreparedMap, Error>)| {
+        map.and_then(|map| save_map(map, &audio, options, &|p| progress(path, p)))
+    };
+    let results = if options.jobs > 1 {
+        paths.par_iter().zip(maps).map(save).collect::<Vec<_>>()
+    } else {
+        paths.iter().zip(maps).map(save).collect()
+    };
+
+    // Don't leave stretched audio behind if every rate that would have used it failed. Audio that was already there
+    // (i.e. from an earlier run for another difficulty) is left alone, since maps outside this batch may use it.
+    let used = results.iter().flatten().flatten().filter_map(|g| g.audio_path.as_ref()).collect::<HashSet<_>>();
+    let created = audio.values().filter(|a| a.created).filter_map(|a| a.path.as_ref().ok()?.as_ref());
+    for audio_path in created.filter(|&p| !used.contains(p)) {
+        let _ = fs::remove_file(audio_path);
+    }
+    results
+}
+
+// A map whose rates have been changed in memory, but not saved yet (see `prepare_map`).
+struct PreparedMap {
+    path: PathBuf, // This is canonicalized.
+    name: String, // See `beatmap_stem`.
+    storyboard: Option<PathBuf>, // This is `None` if the map has no .osb storyboard, or it isn't rated.
+    rates: Vec<(f64, Result<PreparedRate, Error>)>,
+}
+
+// A rate of a map that has been changed in memory (see `prepare_rate`).
+struct PreparedRate {
+    map: Beatmap,
+    new_path: PathBuf, // Where the rated map is saved.
+    storyboard: Option<String>, // The rated storyboard, if the map's storyboard is rated.
+    audio: Option<AudioKey>, // The audio the rate uses, if any is stretched for it (see `stretch_audio`).
+}
+
+// The audio stretched for a rate of a map, which is the same for every difficulty of a set that uses the same audio
+// file and is saved in the same place. The paths are canonicalized if they can be, so that different ways of referring
+// to the same audio file (i.e. from a subdirectory) are still the same.
+#[derive(Clone, Eq, Hash, PartialEq)]
+struct AudioKey {
+    source: PathBuf,
+    output_dir: PathBuf,
+    rate: u64, // The bits of the rate, since `f64` can't be hashed.
+}
+
+impl AudioKey {
+    fn new(map_path: &Path, map: &Beatmap, output_dir: &Path, rate: f64) -> Self {
+        let parent_dir = map_path.parent().unwrap_or_else(|| Path::new("./"));
+        let source = audio::audio_source_path(parent_dir, &map.general_info.audio_file);
+        let canonicalize = |path: PathBuf| path.canonicalize().unwrap_or(path);
+        let output_dir = canonicalize(output_dir.to_path_buf());
+        AudioKey { source: canonicalize(source), output_dir, rate: rate.to_bits() }
+    }
+}
+
+// The audio stretched for an `AudioKey` (see `stretch_audio`).
+struct StretchedFile {
+    path: Result<Option<PathBuf>, Error>, // This is `None` if the original audio is used as-is.
+    created: bool, // Whether the file at `path` didn't exist before, so that it's only removed if it was created.
+}
+
+// Reads the map at `path` and checks that its rates can be generated, changing the rate of a copy of it for each rate
+// (see `prepare_rate`). An error is returned if the map can't be generated at all, while errors in single rates are
+// kept with their rates.
+fn prepare_map(
+    path: &Path,
+    rates: &Rates,
+    options: &Options,
+    progress: &(dyn Fn(Progress) + Sync),
+) -> Result<PreparedMap, Error> {
+    check_cancelled()?;
+    let path = path.canonicalize().map_err(|_| Error::new(EXIT_BEATMAP, "couldn't find file"))?;
+    let base_map_name = beatmap_stem(&path).ok_or_else(|| Error::new(EXIT_BEATMAP, "not a file"))?;
+    if beatmap_extension(&path).filter(|e| e.eq_ignore_ascii_case("osu")).is_none() {
+        util::log_warn(format!("{} doesn't have a .osu extension; is it really a beatmap?", path.display()));
+    }
+    let map = read_beatmap(&path).map_err(|e| Error::new(EXIT_BEATMAP, e))?;
+    if let (Some(rate), false) = (map.rate_suffix(), options.preview_only) {
+        let diff_name = &map.metadata.diff_name;
+        let hint = "generate rates from the original map instead";
+        return Err(format!("\"{}\" is already a {}x rate; {}", diff_name, rate, hint).into());
+    }
+    let rates = match rates {
+        Rates::Fixed(rates) => rates.clone(),
+        Rates::TargetBpm(bpm) => vec![rate_for_bpm(&map, *bpm, options.min_rate, &base_map_name)?],
+    };
+
+    // Check that no two rates would be saved to the same file (i.e. "1.2" and "120%"), and that no rate would overwrite
+    // the map itself (i.e. with a `--filename-format` matching its name), before generating any of them.
+    if !options.preview_only {
+        let new_paths = rates.iter().map(|&r| (r, rated_map_path(&path, &map.metadata.diff_name, r, options)));
+        let new_paths = new_paths.collect::<Vec<_>>();
+        if let Some((rate, _)) = new_paths.iter().find(|(_, p)| util::is_same_file(&path, p)) {
+            return Err(format!("the {}x rate would be saved as the original map, overwriting it", rate).into());
+        }
+        let mut new_paths = new_paths.into_iter().map(|(_, p)| p).collect::<Vec<_>>();
+        new_paths.sort();
+        if let Some(pair) = new_paths.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(format!("more than one rate would be saved as {}", pair[0].display()).into());
+        }
+    }
+    // At 1.0x, the original audio is reused (see `audio::stretch_beatmap_audio`), so it wouldn't be changed at all.
+    let changes_audio = options.pitch != 0. || options.loudness_target.is_some();
+    if changes_audio && !options.no_audio && rates.contains(&1.) {
+        let hint = "use `osurate audio` to only change the audio";
+        return Err(format!("audio can't be pitch shifted or loudness matched at 1.0x; {}", hint).into());
+    }
+    progress(Progress::ParsedMap { map_name: &base_map_name, n_rates: rates.len() });
+
+    // osu! loads the same storyboard for every difficulty in a folder, so rating it for one rate (without overwriting
+    // the original) is only possible if each rate is saved in a folder of its own.
+    let storyboard = if options.preview_only { None } else { find_storyboard(&path) };
+    if let (Some(storyboard), false) = (&storyboard, options.rate_folders) {
+        let name = storyboard.file_name().unwrap().to_string_lossy();
+        util::log_warn(format!("{} is shared with the original map, so it's only rated with --rate-folders", name));
+    }
+    let storyboard = storyboard.filter(|_| options.rate_folders);
+
+    // Since the map is mutated by `change_rate`, inaccuracies may accumulate when reverting a rate change. To work
+    // around this, the beatmap is cloned for each rate.
+    let prepare = |&rate: &f64| (rate, prepare_rate(map.clone(), rate, &path, storyboard.as_deref(), options));
+    let rates = rates.iter().map(prepare).collect();
+    Ok(PreparedMap { path, name: base_map_name, storyboard, rates })
+}
+
+// Changes the rate of `map` (the map at `path`) and its storyboard at `storyboard` (if any) to `rate` in memory, and
+// creates the folder the rate is saved in.
+fn prepare_rate(
+    mut map: Beatmap,
+    rate: f64,
+    path: &Path,
+    storyboard: Option<&Path>,
+    options: &Options,
+) -> Result<PreparedRate, Error> {
+    if options.preview_only {
+        map.change_preview_time(rate, &options.rate_options);
+        return Ok(PreparedRate { map, new_path: path.to_path_buf(), storyboard: None, audio: None });
+    }
+
+    // The rate folder is only created once the map (and its storyboard) could be rated, so that no empty folder is left
+    // behind otherwise.
+    let new_path = rated_map_path(path, &map.metadata.diff_name, rate, options);
+    let output_dir = new_path.parent().unwrap();
+    change_rate(&mut map, rate, &options.rate_options)?;
+    let storyboard = storyboard.map(|s| rate_storyboard(s, rate, options)).transpose()?;
+    if options.rate_folders {
+        fs::create_dir_all(output_dir).map_err(|_| "couldn't create rate folder")?;
+    }
+    let audio = if options.no_audio { None } else { Some(AudioKey::new(path, &map, output_dir, rate)) };
+    Ok(PreparedRate { map, new_path, storyboard, audio })
+}
+
+// Stretches the audio used by the rates of `maps` (with the paths they were given as), once for each `AudioKey`,
+// returning the stretched audio for each. Every map that uses stretched audio has it reported to `progress`. Nothing
+// more is stretched once `CANCELLED` is set.
+fn stretch_audio<'a>(
+    maps: &[(&'a Path, &PreparedMap)],
+    options: &Options,
+    progress: &(dyn Fn(&'a Path, Progress) + Sync),
+) -> HashMap<AudioKey, StretchedFile> {
+    // The rates using each audio, which is stretched in the order it's first used in (at least without `jobs`).
+    let (mut keys, mut users) = (vec![], HashMap::<_, Vec<_>>::new());
+    for &(path, map) in maps {
+        for (rate, prepared) in &map.rates {
+            if let Ok(prepared @ PreparedRate { audio: Some(key), .. }) = prepared {
+                if !users.contains_key(key) {
+                    keys.push(key);
+                }
+                users.entry(key).or_default().push((path, map, *rate, prepared));
+            }
+        }
+    }
+
+    let stretch = |&key: &&AudioKey| {
+        let key_users = &users[key];
+        let mut created = false;
+        let path = check_cancelled().and_then(|_| {
+            let (_, map, rate, prepared) = key_users[0];
+            let native = audio::Mp3Stretcher {
+                n_threads: options.threads,
+                semitones: options.pitch,
+                loudness_target: options.loudness_target,
+                anti_alias: options.anti_alias,
+            };
+            let stretcher = audio_stretcher(options.ffmpeg, native);
+            let parent_dir = map.path.parent().unwrap_or_else(|| Path::new("./"));
+            let output_dir = prepared.new_path.parent().unwrap();
+            let mut map = prepared.map.clone(); // Only its `AudioFilename` is changed.
+            let new_path = audio::stretched_audio_path(&map, parent_dir, output_dir, rate)?;
+            created = matches!(new_path, Some(new_path) if !new_path.exists());
+            Ok(audio::stretch_beatmap_audio(&mut map, parent_dir, output_dir, rate, &*stretcher)?)
+        });
+        if let Ok(Some(_)) = &path {
+            for &(path, map, rate, _) in key_users {
+                progress(path, Progress::StretchedAudio { map_name: &map.name, rate });
+            }
+        }
+        (key.clone(), StretchedFile { path, created })
+    };
+    if options.jobs > 1 {
+        keys.par_iter().map(stretch).collect()
+    } else {
+        keys.iter().map(stretch).collect()
+    }
+}
+
+// Saves the rates of `map` that were prepared, with the audio stretched for them in `audio` (see `stretch_audio`),
+// returning the files created for each rate that was saved.
+fn save_map(
+    map: PreparedMap,
+    audio: &HashMap<AudioKey, StretchedFile>,
+    options: &Options,
+    progress: &(dyn Fn(Progress) + Sync),
+) -> Result<Vec<GeneratedRate>, Error> {
+    let PreparedMap { path, name, storyboard, rates } = map;
+    let save = |(rate, prepared): (f64, Result<PreparedRate, Error>)| {
+        let prepared = prepared?;
+        // Rates with stretched audio are always saved, so that the work on their audio isn't wasted.
+        let audio_path = match &prepared.audio {
+            Some(key) => audio[key].path.clone()?,
+            _ => check_cancelled().map(|_| None)?,
+        };
+        let generated_rate = save_rate(prepared, rate, &path, storyboard.as_deref(), audio_path, options)?;
+        progress(Progress::WroteMap { map_name: &name, generated: &generated_rate });
+        Ok(generated_rate)
+    };
+
+    // Every rate is attempted even if another one fails, so that an error (i.e. from LAME) in one rate doesn't stop the
+    // others from finishing. If any rate was generated, the ones that failed (but weren't cancelled) are reported to
+    // `progress` as `RateFailed`. Otherwise, the map failed as a whole, and the first error is returned.
+    let rate_list = rates.iter().map(|(rate, _)| *rate).collect::<Vec<_>>();
+    let results = if options.jobs > 1 {
+        rates.into_par_iter().map(save).collect::<Vec<_>>()
+    } else {
+        rates.into_iter().map(save).collect()
+    };
+    let (mut generated, mut failed) = (vec![], vec![]);
+    for (rate, result) in rate_list.into_iter().zip(results) {
+        match result {
+            Ok(generated_rate) => generated.push(generated_rate),
+            Err(e) => failed.push((rate, e)),
+        }
+    }
+    if generated.is_empty() && !failed.is_empty() {
+        return Err(failed.swap_remove(0).1);
+    }
+    for (rate, error) in failed.iter().filter(|(_, e)| e.exit_code != EXIT_CANCELLED) {
+        progress(Progress::RateFailed { map_name: &name, rate: *rate, error });
+    }
+    Ok(generated)
+}
+
+// Saves the given prepared rate of the map at `path` (and of its storyboard at `storyboard`, if any), which uses the
+// stretched audio at `audio_path` (if any).
+fn save_rate(
+    prepared: PreparedRate,
+    rate: f64,
+    path: &Path,
+    storyboard: Option<&Path>,
+    audio_path: Option<PathBuf>,
+    options: &Options,
+) -> Result<GeneratedRate, Error> {
+    let PreparedRate { mut map, new_path, storyboard: rated_storyboard, .. } = prepared;
+    if options.preview_only {
+        // Compressed maps are kept compressed. Since the map is overwritten, its modification time is read beforehand.
+        let mtime = if options.preserve_mtime { fs::metadata(path).ok() } else { None };
+        let mtime = mtime.map(|m| FileTime::from_last_modification_time(&m));
+        let length = map.length();
+        util::write_atomically(path, "couldn't overwrite beatmap file".to_string(), |file| {
+            let map = map.into_string();
+            let result = if is_gzipped(path) {
+                let mut encoder = GzEncoder::new(file, Compression::default());
+                encoder.write_all(map.as_bytes()).and_then(|_| encoder.finish().map(|_| ()))
+            } else {
+                file.write_all(map.as_bytes())
+            };
+            result.map_err(|_| "couldn't write beatmap file".to_string())
+        })?;
+        if let Some(mtime) = mtime {
+            set_mtime(path, mtime);
+        }
+        let map_path = path.to_path_buf();
+        return Ok(GeneratedRate { rate, length, map_path, audio_path: None, storyboard_path: None });
+    }
+
+    let parent_dir = path.parent().unwrap_or_else(|| Path::new("./"));
+    let output_dir = new_path.parent().unwrap();
+    let audio_source_path = audio::audio_source_path(parent_dir, &map.general_info.audio_file);
+    if let Some(audio_path) = &audio_path {
+        // This is what `audio::stretch_beatmap_audio` changes it to.
+        map.general_info.audio_file = audio_path.file_name().unwrap().to_string_lossy().to_string();
+    }
+
+    let result = util::write_atomically(&new_path, "couldn't create new beatmap file".to_string(), |file| {
+        write_beatmap(map, file)
+    });
+    let result = result.and_then(|length| {
+        let storyboard = storyboard.zip(rated_storyboard.as_deref());
+        let storyboard_path = storyboard.map(|(s, rated)| write_storyboard_rate(s, rated, output_dir)).transpose();
+        if storyboard_path.is_err() {
+            let _ = fs::remove_file(&new_path);
+        }
+        Ok((length, storyboard_path?))
+    });
+    if options.preserve_mtime && result.is_ok() {
+        copy_mtime(path, &new_path);
+        if let Some(audio_path) = &audio_path {
+            copy_mtime(&audio_source_path, audio_path);
+        }
+        if let (Some(storyboard), Ok((_, Some(storyboard_path)))) = (storyboard, &result) {
+            copy_mtime(storyboard, storyboard_path);
+        }
+    }
+    let (length, storyboard_path) = result?;
+    Ok(GeneratedRate { rate, length, map_path: new_path, audio_path, storyboard_path })
+}
+
+// Changes the rate of `map` like `Beatmap::change_rate`, warning about anything in the rated map that osu! won't play
+// as expected (i.e. hit objects that start before the audio).
+pub fn change_rate(map: &mut Beatmap, rate: f64, options: &RateOptions) -> Result<(), String> {
+    map.change_rate(rate, options).map_err(|e| e.to_string())?;
+    let n_unhittable = map.hit_objects_before_start();
+    if n_unhittable > 0 {
+        util::log_warn(format!("{} hit object(s) start before the audio at {}x and can't be hit", n_unhittable, rate));
+    }
+    let n_clamped = map.slider_velocities_out_of_range();
+    if n_clamped > 0 {
+        util::log_warn(format!("{} slider velocity change(s) are outside of what osu! allows (0.1x-10x)", n_clamped));
+    }
+    Ok(())
+}
+
+// Changes the rate of `map` and writes the result to `out`, returning the length of the rated map. This doesn't touch
+// the audio or the filesystem, so the map's `AudioFilename` is left as-is.
+pub fn generate_rate_to_writer(
+    mut map: Beatmap,
+    rate: f64,
+    options: &RateOptions,
+    out: impl Write,
+) -> Result<f64, String> {
+    change_rate(&mut map, rate, options)?;
+    write_beatmap(map, out)
+}
+
+// Writes `map` to `out`, returning its length.
+pub fn write_beatmap(map: Beatmap, mut out: impl Write) -> Result<f64, String> {
+    let length = map.length();
+    out.write_all(map.into_string().as_bytes()).map_err(|_| "couldn't write new beatmap file".to_string())?;
+    Ok(length)
+}
+
+// Finds the storyboard (.osb file) of the map at `path`, if it has one. osu! names it after the beatmap set (i.e.
+// "Artist - Title (Mapper).osb" for "Artist - Title (Mapper) [Diff].osu"), but other names are accepted too if the
+// map's folder only has one storyboard.
+fn find_storyboard(path: &Path) -> Option<PathBuf> {
+    let map_stem = beatmap_stem(path)?;
+    let entries = fs::read_dir(path.parent()?).ok()?.filter_map(|e| e.ok()).map(|e| e.path());
+    let is_storyboard = |p: &PathBuf| p.is_file() && p.extension().filter(|e| e.eq_ignore_ascii_case("osb")).is_some();
+    let storyboards = entries.filter(is_storyboard).collect::<Vec<_>>();
+
+    let stem_matches = |p: &&PathBuf| p.file_stem().filter(|s| map_stem.starts_with(&*s.to_string_lossy())).is_some();
+    match (storyboards.iter().find(stem_matches), storyboards.as_slice()) {
+        (Some(storyboard), _) | (_, [storyboard]) => Some(storyboard.clone()),
+        _ => None,
+    }
+}
+
+// Reads the storyboard at `source` and changes its rate to `rate`, returning the rated storyboard.
+fn rate_storyboard(source: &Path, rate: f64, options: &Options) -> Result<String, String> {
+    let data = fs::read(source).map_err(|_| "couldn't read storyboard file")?;
+    let source_text = match util::decode_utf16(&data) {
+        Some(text) => text,
+        _ => String::from_utf8(data).map_err(|_| "invalid utf-8 text in storyboard")?,
+    };
+    let text = source_text.trim_start_matches('\u{feff}');
+    storyboard::change_rate(text, rate, &options.rate_options).map_err(|e| e.to_string())
+}
+
+// Saves `rated`, a rate of the storyboard at `source` (see `rate_storyboard`), in `output_dir`, returning the path of
+// the rated storyboard. Its name is kept, so that osu! still loads it for the rated map.
+fn write_storyboard_rate(source: &Path, rated: &str, output_dir: &Path) -> Result<PathBuf, String> {
+    let new_path = output_dir.join(source.file_name().unwrap());
+    util::verify(!util::is_same_file(source, &new_path), "storyboard would overwrite itself".to_string())?;
+    util::write_atomically(&new_path, "couldn't create new storyboard file".to_string(), |file| {
+        file.write_all(rated.as_bytes()).map_err(|_| "couldn't write storyboard file".to_string())
+    })?;
+    Ok(new_path)
+}
+
+// Gives the file at `path` the modification time of the file at `source` (see `--preserve-mtime`). The generated file
+// is still usable if this fails, so only a warning is logged.
+fn copy_mtime(source: &Path, path: &Path) {
+    match fs::metadata(source) {
+        Ok(metadata) => set_mtime(path, FileTime::from_last_modification_time(&metadata)),
+        _ => util::log_warn(format!("couldn't read the modification time of {}", source.display())),
+    }
+}
+
+fn set_mtime(path: &Path, mtime: FileTime) {
+    if filetime::set_file_mtime(path, mtime).is_err() {
+        util::log_warn(format!("couldn't set the modification time of {}", path.display()));
+    }
+}
+
+// Returns the path that the given rate of the map at `path` (with the difficulty name `diff_name`) is saved to.
+fn rated_map_path(path: &Path, diff_name: &str, rate: f64, options: &Options) -> PathBuf {
+    let parent_dir = path.parent().unwrap_or_else(|| Path::new("./"));
+    let output_dir = options.output_dir.as_deref().unwrap_or(parent_dir);
+    let output_dir = if options.rate_folders {
+        output_dir.join(format!("rate_{}", rate))
+    } else {
+        output_dir.to_path_buf()
+    };
+
+    let old_file_name = beatmap_stem(path).unwrap();
+    let new_file_name = match &options.filename_format {
+        Some(format) => format_file_name(format, &old_file_name, diff_name, rate),
+        _ => rated_file_name(&old_file_name, rate),
+    };
+    output_dir.join(util::sanitize_file_name(&new_file_name))
+}
+
+// Reads and parses the beatmap at `path`, which may be gzip compressed.
+pub fn read_beatmap(path: &Path) -> Result<Beatmap, String> {
+    let map_file = File::open(path).map_err(|_| "couldn't open file")?;
+    if is_gzipped(path) {
+        parse_beatmap(GzDecoder::new(map_file))
+    } else {
+        parse_beatmap(map_file)
+    }
+}
+
+// Parses a beatmap read from `reader`. Some editors save maps as UTF-16 (with a byte order mark), which is transcoded
+// to UTF-8 first. Rated maps are always saved as UTF-8, which osu! reads just as well.
+pub fn parse_beatmap(reader: impl Read) -> Result<Beatmap, String> {
+    let mut reader = BufReader::new(reader);
+    let start = reader.fill_buf().map_err(|_| "couldn't read file")?;
+    let map = if start.starts_with(&[0xff, 0xfe]) || start.starts_with(&[0xfe, 0xff]) {
+        let mut data = vec![];
+        reader.read_to_end(&mut data).map_err(|_| "couldn't read file")?;
+        let source = util::decode_utf16(&data).ok_or("invalid utf-16 text in beatmap")?;
+        Beatmap::parse(source.as_bytes())
+    } else {
+        Beatmap::parse(reader)
+    };
+    map.map_err(|e| e.to_string())
+}
+
+// Checks if the beatmap at `path` is gzip compressed (i.e. "map.osu.gz").
+pub fn is_gzipped(path: &Path) -> bool {
+    path.extension().filter(|e| e.eq_ignore_ascii_case("gz")).is_some()
+}
+
+// Returns the file stem of the beatmap at `path`, without the .gz extension of compressed beatmaps.
+pub fn beatmap_stem(path: &Path) -> Option<String> {
+    let path = if is_gzipped(path) { Path::new(path.file_stem()?) } else { path };
+    Some(path.file_stem()?.to_string_lossy().to_string())
+}
+
+// Returns the extension of the beatmap at `path`, like `beatmap_stem`.
+pub fn beatmap_extension(path: &Path) -> Option<&OsStr> {
+    let path = if is_gzipped(path) { Path::new(path.file_stem()?) } else { path };
+    path.extension()
+}
+
+// Returns the rate that brings the BPM of the first uninherited timing point of `map` to `target_bpm`. The rate is
+// rounded to the nearest 0.001x to keep file and difficulty names readable.
+fn rate_for_bpm(map: &Beatmap, target_bpm: f64, min_rate: f64, map_name: &str) -> Result<f64, String> {
+    let bpm = map.primary_bpm().ok_or("beatmap has no uninherited timing points")?;
+    let (min_bpm, max_bpm) = map.bpm_range().unwrap_or((bpm, bpm));
+    if max_bpm - min_bpm > 0.001 {
+        util::log_warn(format!("{} has multiple bpms; scaling relative to the first ({})", map_name, bpm));
+    }
+
+    let rate = (target_bpm / bpm * 1000.).round() / 1000.;
+    util::verify(rate >= min_rate && rate > 0., format!("{} bpm would need a rate below {}", target_bpm, min_rate))?;
+    Ok(rate)
+}
+
+// Returns the file name of the rated map for a map with the file stem `stem`. The rate is put in the difficulty name
+// part (i.e. "Artist - Title (Mapper) [Diff (1.2x)].osu") if the stem follows that convention, and appended otherwise.
+pub fn rated_file_name(stem: &str, rate: f64) -> String {
+    match stem.strip_suffix(']') {
+        Some(stem) => format!("{} ({}x)].osu", stem, rate),
+        _ => format!("{} ({}x).osu", stem, rate),
+    }
+}
+
+// Returns the file name given by the user-specified `format`, where "{name}" is replaced with the file stem of the
+// original map, "{diff}" with its difficulty name, and "{rate}" with the rate.
+pub fn format_file_name(format: &str, stem: &str, diff_name: &str, rate: f64) -> String {
+    format.replace("{name}", stem).replace("{diff}", diff_name).replace("{rate}", &rate.to_string())
+}
+
+// Checks that file names created with `format` are for .osu files and differ between rates, so that rates can't
+// overwrite each other.
+pub fn verify_file_name_format(format: &str) -> Result<(), String> {
+    util::verify(format.to_ascii_lowercase().ends_with(".osu"), "file name format must end with .osu".to_string())?;
+    util::verify(format.contains("{rate}"), "file name format must contain {rate}".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    use filetime::FileTime;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    use crate::beatmap::{parse_fixture, Beatmap, RateOptions};
+    use crate::generate::{
+        beatmap_stem, EXIT_AUDIO_SOURCE, EXIT_BEATMAP, EXIT_FAILURE, format_file_name, generate_batch,
+        generate_rate_to_writer, generate_rates, Options, parse_beatmap, Progress, Rates, rated_file_name,
+        verify_file_name_format,
+    };
+    use crate::test_util::{fixture, TestDir};
+
+    #[test]
+    fn generate_rate_to_writer_writes_rated_map() {
+        let map = parse_fixture("standard");
+        let mut out = vec![];
+        generate_rate_to_writer(map, 1.5, &RateOptions::default(), &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("osu file format v14\n"));
+        assert!(out.contains("\nVersion:Annihilation (1.5x)\n"));
+    }
+
+    #[test]
+    fn rated_file_name_puts_rate_in_difficulty_name() {
+        let name = rated_file_name("Frums - Wanderflux (osurate) [Annihilation]", 1.2);
+        assert_eq!(name, "Frums - Wanderflux (osurate) [Annihilation (1.2x)].osu");
+    }
+
+    #[test]
+    fn rated_file_name_appends_rate_without_difficulty_name() {
+        assert_eq!(rated_file_name("wanderflux", 0.85), "wanderflux (0.85x).osu");
+        assert_eq!(rated_file_name("[Annihilation] backup", 1.1), "[Annihilation] backup (1.1x).osu");
+        assert_eq!(rated_file_name("", 1.5), " (1.5x).osu");
+    }
+
+    #[test]
+    fn format_file_name_replaces_placeholders() {
+        let name = format_file_name("{name} - {diff} @ {rate}x.osu", "wanderflux", "Annihilation", 1.2);
+        assert_eq!(name, "wanderflux - Annihilation @ 1.2x.osu");
+        assert_eq!(format_file_name("{rate}{rate}.osu", "", "", 0.85), "0.850.85.osu");
+    }
+
+    #[test]
+    fn verify_file_name_format_requires_extension_and_rate() {
+        assert!(verify_file_name_format("{name} ({rate}x).osu").is_ok());
+        assert!(verify_file_name_format("{rate}.OSU").is_ok());
+        assert!(verify_file_name_format("{name} ({rate}x)").is_err());
+        assert!(verify_file_name_format("{name} [{diff}].osu").is_err());
+    }
+
+    #[test]
+    fn beatmap_stem_ignores_gz_extension() {
+        assert_eq!(beatmap_stem(Path::new("maps/A - B (m) [C].osu.gz")).unwrap(), "A - B (m) [C]");
+        assert_eq!(beatmap_stem(Path::new("maps/A - B (m) [C].osu")).unwrap(), "A - B (m) [C]");
+        assert_eq!(beatmap_stem(Path::new("maps/backup.gz")).unwrap(), "backup");
+    }
+
+    #[test]
+    fn gzipped_maps_are_decompressed() {
+        let dir = TestDir::new("gzipped_maps_are_decompressed");
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(fixture("standard").as_bytes()).unwrap();
+        fs::write(dir.join("map [Annihilation].osu.gz"), encoder.finish().unwrap()).unwrap();
+
+        let options = Options { no_audio: true, ..Default::default() };
+        let path = dir.join("map [Annihilation].osu.gz");
+        let generated = generate_rates(&path, &Rates::Fixed(vec![1.2]), &options, &|_| {});
+        let map_path = &generated.unwrap()[0].map_path;
+        assert_eq!(map_path.file_name().unwrap(), "map [Annihilation (1.2x)].osu");
+        assert!(fs::read_to_string(map_path).unwrap().contains("\nVersion:Annihilation (1.2x)\n"));
+    }
+
+    #[test]
+    fn progress_is_reported_for_each_rate() {
+        let dir = TestDir::new("progress_is_reported_for_each_rate");
+        fs::write(dir.join("map.osu"), fixture("standard")).unwrap();
+
+        let events = Mutex::new(vec![]);
+        let options = Options { no_audio: true, ..Default::default() };
+        let generated = generate_rates(&dir.join("map.osu"), &Rates::Fixed(vec![1.1, 1.2]), &options, &|p| {
+            events.lock().unwrap().push(match p {
+                Progress::ParsedMap { map_name, n_rates } => format!("parsed {} {}", map_name, n_rates),
+                Progress::StretchedAudio { rate, .. } => format!("stretched {}", rate),
+                Progress::WroteMap { generated, .. } => format!("wrote {}", generated.rate),
+                Progress::RateFailed { rate, .. } => format!("failed {}", rate),
+            });
+        });
+        assert_eq!(generated.unwrap().len(), 2);
+        assert_eq!(events.into_inner().unwrap(), ["parsed map 2", "wrote 1.1", "wrote 1.2"]);
+    }
+
+    #[test]
+    fn failed_rates_dont_stop_the_others() {
+        let dir = TestDir::new("failed_rates_dont_stop_the_others");
+        fs::write(dir.join("map.osu"), fixture("standard")).unwrap();
+        fs::write(dir.join("rate_1.2"), "").unwrap(); // Keeps the folder for the 1.2x rate from being created.
+
+        let failed = Mutex::new(vec![]);
+        let options = Options { no_audio: true, rate_folders: true, ..Default::default() };
+        let generated = generate_rates(&dir.join("map.osu"), &Rates::Fixed(vec![1.1, 1.2]), &options, &|p| {
+            if let Progress::RateFailed { rate, .. } = p {
+                failed.lock().unwrap().push(rate);
+            }
+        });
+        let generated = generated.unwrap();
+        assert_eq!(generated.len(), 1);
+        assert_eq!(generated[0].rate, 1.1);
+        assert_eq!(failed.into_inner().unwrap(), [1.2]);
+
+        // If every rate fails, the map fails as a whole.
+        let generated = generate_rates(&dir.join("map.osu"), &Rates::Fixed(vec![1.2]), &options, &|_| {});
+        assert!(generated.is_err());
+    }
+
+    #[test]
+    fn rates_saved_to_the_same_file_are_rejected() {
+        let dir = TestDir::new("rates_saved_to_the_same_file_are_rejected");
+        fs::write(dir.join("map.osu"), fixture("standard")).unwrap();
+
+        let options = Options { no_audio: true, ..Default::default() };
+        let generated = generate_rates(&dir.join("map.osu"), &Rates::Fixed(vec![1.2, 1.1, 1.2]), &options, &|_| {});
+        assert!(matches!(generated, Err(e) if e.message.contains("map (1.2x).osu")));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn rates_saved_as_the_original_map_are_rejected() {
+        let dir = TestDir::new("rates_saved_as_the_original_map_are_rejected");
+        fs::write(dir.join("map 1.2x.osu"), fixture("standard")).unwrap();
+
+        let filename_format = Some("map {rate}x.osu".to_string());
+        let options = Options { no_audio: true, filename_format, ..Default::default() };
+        let generated = generate_rates(&dir.join("map 1.2x.osu"), &Rates::Fixed(vec![1.1, 1.2]), &options, &|_| {});
+        assert!(matches!(generated, Err(e) if e.message.contains("1.2x rate would be saved as the original map")));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(fs::read_to_string(dir.join("map 1.2x.osu")).unwrap(), fixture("standard"));
+    }
+
+    #[test]
+    fn rates_too_long_for_osu_fail_before_audio_is_stretched() {
+        let dir = TestDir::new("rates_too_long_for_osu_fail_before_audio_is_stretched");
+        let source = &fixture("standard");
+        fs::write(dir.join("map.osu"), source.replace("2,8000,11000", "2,8000,30000000")).unwrap();
+        fs::write(dir.join("map.osb"), "[Events]\nSample,30000000,0,\"clap.wav\",100\n").unwrap();
+        fs::write(dir.join("storyboarded.osu"), source).unwrap();
+
+        // The map's audio isn't there, so these would fail with `EXIT_AUDIO_SOURCE` if it were stretched first.
+        let options = Options { rate_folders: true, ..Default::default() };
+        for name in ["map.osu", "storyboarded.osu"].iter() {
+            let generated = generate_rates(&dir.join(name), &Rates::Fixed(vec![0.01]), &options, &|_| {});
+            assert!(matches!(generated, Err(e) if e.exit_code == EXIT_FAILURE && e.message.contains("too long")));
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn errors_have_exit_codes() {
+        let dir = TestDir::new("errors_have_exit_codes");
+        fs::write(dir.join("map.osu"), fixture("standard")).unwrap();
+        fs::write(dir.join("invalid.osu"), "not a beatmap").unwrap();
+
+        let rates = Rates::Fixed(vec![1.2]);
+        let exit_code = |path: &str| match generate_rates(&dir.join(path), &rates, &Options::default(), &|_| {}) {
+            Err(e) => e.exit_code,
+            _ => 0,
+        };
+        assert_eq!(exit_code("missing.osu"), EXIT_BEATMAP);
+        assert_eq!(exit_code("invalid.osu"), EXIT_BEATMAP);
+        assert_eq!(exit_code("map.osu"), EXIT_AUDIO_SOURCE); // The map's audio isn't there.
+    }
+
+    #[test]
+    fn pitch_shifts_are_rejected_at_normal_rate() {
+        let dir = TestDir::new("pitch_shifts_are_rejected_at_normal_rate");
+        fs::write(dir.join("map.osu"), fixture("standard")).unwrap();
+
+        let options = Options { pitch: 2., ..Default::default() };
+        let generated = generate_rates(&dir.join("map.osu"), &Rates::Fixed(vec![1.2, 1.]), &options, &|_| {});
+        assert!(matches!(generated, Err(e) if e.message.contains("1.0x")));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn utf16_beatmaps_are_parsed() {
+        let source = &fixture("standard");
+        let utf16 = [0xff, 0xfe].iter().copied().chain(source.encode_utf16().flat_map(u16::to_le_bytes));
+        let map = parse_beatmap(utf16.collect::<Vec<_>>().as_slice()).unwrap();
+        assert_eq!(map.into_string(), Beatmap::parse(source.as_bytes()).unwrap().into_string());
+    }
+
+    #[test]
+    fn modification_times_can_be_preserved() {
+        let dir = TestDir::new("modification_times_can_be_preserved");
+        fs::write(dir.join("map.osu"), fixture("standard")).unwrap();
+        let mtime = FileTime::from_unix_time(1_600_000_000, 0);
+        filetime::set_file_mtime(dir.join("map.osu"), mtime).unwrap();
+
+        let options = Options { no_audio: true, preserve_mtime: true, ..Default::default() };
+        let generated = generate_rates(&dir.join("map.osu"), &Rates::Fixed(vec![1.2]), &options, &|_| {}).unwrap();
+        let metadata = fs::metadata(&generated[0].map_path).unwrap();
+        assert_eq!(FileTime::from_last_modification_time(&metadata), mtime);
+    }
+
+    #[test]
+    fn storyboards_are_rated_in_rate_folders() {
+        let dir = TestDir::new("storyboards_are_rated_in_rate_folders");
+        let path = dir.join("Artist - Title (Mapper) [Diff].osu");
+        fs::write(&path, fixture("standard")).unwrap();
+        fs::write(dir.join("Artist - Title (Mapper).osb"), "[Events]\nSample,1000,0,\"clap.wav\",100\n").unwrap();
+        fs::write(dir.join("Other - Song (Mapper).osb"), "[Events]\n").unwrap();
+
+        let options = Options { no_audio: true, rate_folders: true, ..Default::default() };
+        let generated = generate_rates(&path, &Rates::Fixed(vec![2.]), &options, &|_| {}).unwrap();
+        let storyboard_path = generated[0].storyboard_path.as_ref().unwrap();
+        assert_eq!(storyboard_path, &dir.join("rate_2").join("Artist - Title (Mapper).osb"));
+        assert_eq!(fs::read_to_string(storyboard_path).unwrap(), "[Events]\nSample,575,0,\"clap.wav\",100\n");
+
+        // Without rate folders, the original storyboard would have to be overwritten, so it's left alone.
+        let options = Options { no_audio: true, ..Default::default() };
+        let generated = generate_rates(&path, &Rates::Fixed(vec![2.]), &options, &|_| {}).unwrap();
+        assert_eq!(generated[0].storyboard_path, None);
+    }
+
+    #[test]
+    fn audio_shared_by_a_set_is_stretched_once_for_each_rate() {
+        let dir = TestDir::new("audio_shared_by_a_set_is_stretched_once_for_each_rate");
+        fs::create_dir_all(dir.join("out")).unwrap();
+        let source = &fixture("standard");
+        fs::write(dir.join("a.osu"), source).unwrap();
+        fs::write(dir.join("b.osu"), source.replace("Version:Annihilation", "Version:Other")).unwrap();
+        fs::write(dir.join("audio.mp3"), b"audio").unwrap();
+
+        // At 1.0x, the audio is copied to the output folder, which both maps would otherwise do at the same time.
+        let stretched = Mutex::new(vec![]);
+        let options = Options { output_dir: Some(dir.join("out")), jobs: 4, ..Default::default() };
+        let paths = [dir.join("a.osu"), dir.join("b.osu")];
+        let paths = paths.iter().map(|p| p.as_path()).collect::<Vec<_>>();
+        let results = generate_batch(&paths, &Rates::Fixed(vec![1.]), &options, &|path, p| {
+            if let Progress::StretchedAudio { .. } = p {
+                stretched.lock().unwrap().push(path);
+            }
+        });
+        let generated = results.into_iter().map(|r| r.unwrap().remove(0)).collect::<Vec<_>>();
+        assert_eq!(generated[0].audio_path, Some(dir.join("out").join("audio.mp3")));
+        assert_eq!(generated[0].audio_path, generated[1].audio_path);
+        assert_eq!(stretched.into_inner().unwrap().len(), 2);
+        assert_eq!(fs::read(dir.join("out").join("audio.mp3")).unwrap(), b"audio");
+        assert_eq!(fs::read_dir(dir.join("out")).unwrap().count(), 3); // No temporary files are left behind.
+    }
+
+    #[test]
+    fn audio_from_an_earlier_run_is_kept_when_a_map_fails() {
+        let dir = TestDir::new("audio_from_an_earlier_run_is_kept_when_a_map_fails");
+        fs::create_dir_all(dir.join("out")).unwrap();
+        let source = &fixture("standard");
+        fs::write(dir.join("a.osu"), source).unwrap();
+        fs::write(dir.join("b.osu"), source.replace("Version:Annihilation", "Version:Other")).unwrap();
+        fs::write(dir.join("audio.mp3"), b"audio").unwrap();
+
+        let options = Options { output_dir: Some(dir.join("out")), ..Default::default() };
+        let rates = Rates::Fixed(vec![1.]);
+        generate_rates(&dir.join("a.osu"), &rates, &options, &|_| {}).unwrap();
+
+        // The second difficulty can't be written over a directory, but the audio the first one uses has to stay.
+        fs::create_dir_all(dir.join("out").join("b (1x).osu").join("blocked")).unwrap();
+        assert!(generate_rates(&dir.join("b.osu"), &rates, &options, &|_| {}).is_err());
+        assert_eq!(fs::read(dir.join("out").join("audio.mp3")).unwrap(), b"audio");
+    }
+}
@@ -1,24 +1,38 @@
 #![cfg(feature = "gui")]
 
+use std::fs;
 use std::path::PathBuf;
 use std::process;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
+use directories::ProjectDirs;
 use druid::{
-    AppDelegate, AppLauncher, Color, Command, commands, Data, DelegateCtx, Env, FileDialogOptions, FileSpec, Handled,
-    Lens, Target, Widget, WidgetExt, WindowDesc,
+    AppDelegate, AppLauncher, Color, Command, commands, Data, DelegateCtx, Env, Event, EventCtx, ExtEventSink,
+    FileDialogOptions, FileSpec, Handled, KbKey, Key, Lens, Selector, Target, Widget, WidgetExt, WindowDesc,
 };
-use druid::widget::{Button, Flex, Label, LineBreaking, TextBox};
+use druid::theme;
+use druid::widget::{Button, Controller, Flex, Label, LineBreaking, Scroll, TextBox};
+use osurate::generate::{generate_rates, read_beatmap, Options, Progress, Rates, MIN_RATE};
+use osurate::util;
+use serde::{Deserialize, Serialize};
 
-use crate::util;
-
-pub fn run_gui() -> ! {
+// Runs the GUI, with the maps at `files` already selected.
+pub fn run_gui(files: Vec<PathBuf>) -> ! {
     let main_window = WindowDesc::new(make_ui)
         .title("osurate | osu! Rate Generator")
-        .window_size((460., 380.))
+        .window_size((460., 540.))
         .resizable(false);
 
-    let data = AppData { rates_str: Arc::new(String::new()), files: vec![], status: "[Info] started".to_string() };
+    let settings = Settings::load();
+    let data = AppData {
+        rates_str: Arc::new(settings.rates_str),
+        files: files.into_iter().map(SelectedMap::new).collect(),
+        output_dir: settings.output_dir,
+        light_theme: settings.light_theme,
+        log: vec!["[Info] started".to_string()],
+        generating: false,
+    };
     AppLauncher::with_window(main_window).delegate(Delegate {}).launch(data)
         .unwrap_or_else(|_| util::log_fatal("failed to start gui"));
     process::exit(0)
@@ -27,24 +41,96 @@ pub fn run_gui() -> ! {
 #[derive(Clone, Lens)]
 struct AppData {
     rates_str: Arc<String>,
-    files: Vec<PathBuf>,
-    status: String,
+    files: Vec<SelectedMap>,
+    output_dir: Option<PathBuf>, // Rates are saved next to their maps if this is `None`.
+    light_theme: bool, // The dark theme is used if this is false (see `apply_theme`).
+    log: Vec<String>, // Every status message so far, oldest first.
+    generating: bool, // Whether rates are being generated (see `generate`), during which no more can be started.
 }
 
 impl Data for AppData {
     fn same(&self, other: &Self) -> bool {
-        self.rates_str == other.rates_str && self.files == other.files && self.status == other.status
+        self.rates_str == other.rates_str
+            && self.files == other.files
+            && self.output_dir == other.output_dir
+            && self.light_theme == other.light_theme
+            && self.log == other.log
+            && self.generating == other.generating
+    }
+}
+
+// A map selected to generate rates for. Its difficulty name is read once when it's selected, so that the preview of
+// the generated difficulty names doesn't have to parse it again on every change.
+#[derive(Clone, PartialEq)]
+struct SelectedMap {
+    path: PathBuf,
+    diff_name: Result<String, String>, // The error is shown in the preview if the map couldn't be read.
+}
+
+impl SelectedMap {
+    fn new(path: PathBuf) -> Self {
+        let diff_name = read_beatmap(&path).map(|map| map.metadata.diff_name);
+        SelectedMap { path, diff_name }
+    }
+}
+
+// Settings that are remembered between runs. The selected maps aren't included, since they usually change each time.
+#[derive(Default, Serialize, Deserialize)]
+struct Settings {
+    rates_str: String,
+    output_dir: Option<PathBuf>,
+    #[serde(default)] // This wasn't saved by earlier versions.
+    light_theme: bool,
+}
+
+impl Settings {
+    fn path() -> Option<PathBuf> {
+        ProjectDirs::from("", "", "osurate").map(|dirs| dirs.config_dir().join("settings.json"))
+    }
+
+    // Loads the saved settings, falling back to the defaults if there are none or they can't be read.
+    fn load() -> Self {
+        let settings = Self::path().and_then(|path| fs::read_to_string(path).ok());
+        settings.and_then(|s| serde_json::from_str(&s).ok()).unwrap_or_default()
+    }
+
+    // Saves the settings in `data`. Failing to do so isn't a big deal, so it's only logged.
+    fn save(data: &AppData) {
+        let settings = Settings {
+            rates_str: data.rates_str.to_string(),
+            output_dir: data.output_dir.clone(),
+            light_theme: data.light_theme,
+        };
+        let saved = Self::path().and_then(|path| {
+            fs::create_dir_all(path.parent()?).ok()?;
+            fs::write(path, serde_json::to_string(&settings).ok()?).ok()
+        });
+        if saved.is_none() {
+            util::log_warn("couldn't save gui settings");
+        }
     }
 }
 
 struct Delegate;
 
 impl AppDelegate<AppData> for Delegate {
-    // When the user selects a file, store it.
+    // When the user selects a file or output folder, store it. Messages from the thread generating rates (see
+    // `generate`) are added to the log.
     fn command(&mut self, _: &mut DelegateCtx, _: Target, cmd: &Command, data: &mut AppData, _: &Env) -> Handled {
         if let Some(file_info) = cmd.get(commands::OPEN_FILE) {
             let path = file_info.path().to_path_buf();
-            data.files.push(path);
+            if path.is_dir() {
+                data.output_dir = Some(path);
+                Settings::save(data);
+            } else {
+                data.files.push(SelectedMap::new(path));
+            }
+            Handled::Yes
+        } else if let Some(message) = cmd.get(LOG_MESSAGE) {
+            data.log.push(message.clone());
+            Handled::Yes
+        } else if cmd.is(GENERATION_FINISHED) {
+            data.generating = false;
             Handled::Yes
         } else {
             Handled::No
@@ -54,7 +140,14 @@ impl AppDelegate<AppData> for Delegate {
 
 fn make_ui() -> impl Widget<AppData> {
     let rates_input = TextBox::new()
-        .with_placeholder("Rates (i.e. 1.1,1.15,1.2)")
+        .with_placeholder("Rates (i.e. 1.1,1.15x,120%)")
+        .env_scope(|env, rates_str: &Arc<String>| {
+            // The border is only highlighted once something has been entered, so an empty input isn't shown as wrong.
+            if !rates_str.trim().is_empty() && parse_rates(rates_str).is_err() {
+                env.set(theme::BORDER_DARK, INVALID_INPUT_COLOR);
+                env.set(theme::PRIMARY_LIGHT, INVALID_INPUT_COLOR);
+            }
+        })
         .lens(AppData::rates_str)
         .expand_width()
         .padding((6., 7., 6., 2.));
@@ -70,6 +163,25 @@ fn make_ui() -> impl Widget<AppData> {
         })
         .padding(4.);
 
+    let output_dir_button = Button::new("Choose Output Folder")
+        .on_click(|ctx, _, _| {
+            let options = FileDialogOptions::new()
+                .title("Select a folder to save rates in")
+                .button_text("Select")
+                .select_directories();
+            ctx.submit_command(Command::new(commands::SHOW_OPEN_PANEL, options, Target::Auto));
+        })
+        .padding(4.);
+
+    let theme_button = Button::dynamic(|data: &AppData, _| {
+        (if data.light_theme { "Dark Theme" } else { "Light Theme" }).to_string()
+    })
+        .on_click(|_, data: &mut AppData, _| {
+            data.light_theme = !data.light_theme;
+            Settings::save(data);
+        })
+        .padding(4.);
+
     let undo_button = Button::new("Remove Last")
         .on_click(|_, data: &mut AppData, _| { let _ = data.files.pop(); })
         .padding(4.);
@@ -78,41 +190,24 @@ fn make_ui() -> impl Widget<AppData> {
         .on_click(|_, data: &mut AppData, _| data.files.clear())
         .padding(4.);
 
-    // This blocks the UI thread when pressed, not a huge deal though.
-    let generate_button = Button::new("Generate")
-        .on_click(|_, data: &mut AppData, _| {
-            let rates_str = data.rates_str.to_string();
-            let rates_iter = rates_str.split(",").map(|r| r.parse::<f64>());
-            let rates = match rates_iter.collect::<Result<Vec<_>, _>>() {
-                Ok(r) if r.iter().all(|&r| r >= 0.01) => r,
-                _ => {
-                    data.status = "[Error] invalid rate(s) specified".to_string();
-                    return;
-                }
-            };
-
-            // Unlike the CLI version, press on after encountering errors.
-            for file in &data.files {
-                data.status = match crate::generate_rates(file, &rates) {
-                    Err(e) => format!("[Error] {}", e),
-                    Ok(map_name) => format!("[Info] generated rate(s) for {}", map_name),
-                };
-            }
-        })
+    let generate_button = Button::dynamic(|data: &AppData, _| {
+        (if data.generating { "Generating..." } else { "Generate" }).to_string()
+    })
+        .on_click(|ctx, data: &mut AppData, _| generate(data, ctx.get_external_handle()))
         .padding(6.);
 
     let configure_label = |l: Label<AppData>| l
         .with_line_break_mode(LineBreaking::WordWrap)
         .with_text_size(12.)
-        .background(Color::grey(0.12))
-        .border(Color::grey(0.12), 3.)
+        .background(PANEL_COLOR)
+        .border(PANEL_COLOR, 3.)
         .rounded(4.)
         .expand_width();
 
     let selected_maps_label = configure_label(Label::dynamic(
         |data: &AppData, _| {
             let name = data.files.iter()
-                .map(|f| f.file_name().unwrap().to_string_lossy().trim_end_matches(".osu").to_string())
+                .map(|f| f.path.file_name().unwrap().to_string_lossy().trim_end_matches(".osu").to_string())
                 .collect::<Vec<_>>()
                 .join("\n");
             format!("Selected map(s):\n{}", if name.is_empty() { "(none)".to_string() } else { name })
@@ -120,7 +215,21 @@ fn make_ui() -> impl Widget<AppData> {
         .expand_height()
         .padding((6., 1., 6., 6.));
 
-    let status_label = configure_label(Label::dynamic(|data: &AppData, _| data.status.to_string()))
+    let diff_names_label = configure_label(Label::dynamic(|data: &AppData, _| preview_diff_names(data)))
+        .expand_height()
+        .padding((6., 1., 6., 6.));
+
+    let output_dir_label = configure_label(Label::dynamic(|data: &AppData, _| match &data.output_dir {
+        Some(dir) => format!("Output folder: {}", dir.display()),
+        _ => "Output folder: (next to each map)".to_string(),
+    }))
+        .padding((6., 1., 6., 2.));
+
+    // Earlier messages are kept so that errors don't get lost when generating rates for many maps.
+    let log_label = Label::dynamic(|data: &AppData, _| data.log.join("\n"));
+    let log = Scroll::new(configure_label(log_label))
+        .vertical()
+        .expand_height()
         .padding((6., 2., 6., 6.));
 
     Flex::column()
@@ -130,7 +239,154 @@ fn make_ui() -> impl Widget<AppData> {
             .with_child(undo_button)
             .with_child(clear_button)
             .with_child(generate_button))
+        .with_child(Flex::row().with_child(output_dir_button).with_child(theme_button))
         .with_flex_child(selected_maps_label, 1.)
-        .with_child(status_label)
-        .background(Color::grey(0.05))
+        .with_flex_child(diff_names_label, 1.)
+        .with_child(output_dir_label)
+        .with_flex_child(log, 1.)
+        .background(BACKGROUND_COLOR)
+        .controller(GenerateOnEnter)
+        .env_scope(|env, data: &AppData| apply_theme(env, data.light_theme))
+}
+
+// The background colors of the window and of the labels in it.
+const BACKGROUND_COLOR: Key<Color> = Key::new("osurate.background-color");
+const PANEL_COLOR: Key<Color> = Key::new("osurate.panel-color");
+
+// Sets the colors used by the widgets in the window for the light or dark theme. The dark theme keeps Druid's (dark)
+// default colors for everything but the backgrounds.
+fn apply_theme(env: &mut Env, light: bool) {
+    if light {
+        env.set(BACKGROUND_COLOR, Color::grey(0.95));
+        env.set(PANEL_COLOR, Color::grey(0.86));
+        env.set(theme::LABEL_COLOR, Color::grey(0.1));
+        env.set(theme::PLACEHOLDER_COLOR, Color::grey(0.5));
+        env.set(theme::BACKGROUND_LIGHT, Color::WHITE); // The background of the rates input.
+        env.set(theme::BORDER_DARK, Color::grey(0.7));
+        env.set(theme::BORDER_LIGHT, Color::grey(0.45));
+        env.set(theme::BUTTON_DARK, Color::grey(0.8));
+        env.set(theme::BUTTON_LIGHT, Color::grey(0.97));
+        env.set(theme::CURSOR_COLOR, Color::BLACK);
+        env.set(theme::SCROLLBAR_COLOR, Color::grey(0.3));
+    } else {
+        env.set(BACKGROUND_COLOR, Color::grey(0.05));
+        env.set(PANEL_COLOR, Color::grey(0.12));
+    }
+}
+
+// The border color of inputs with invalid contents.
+const INVALID_INPUT_COLOR: Color = Color::rgb8(0xd0, 0x40, 0x40);
+
+// Parses the comma-separated rates in the rates input. If any of them are invalid, the error says which one.
+fn parse_rates(rates_str: &str) -> Result<Vec<f64>, String> {
+    if rates_str.trim().is_empty() {
+        return Err("no rates specified".to_string());
+    }
+    let rates = rates_str.split(',').enumerate().map(|(i, rate)| {
+        let parsed = util::parse_rate(rate).filter(|&r| r >= MIN_RATE);
+        parsed.ok_or_else(|| format!("rate {} (\"{}\") is invalid", i + 1, rate.trim()))
+    });
+    rates.collect()
+}
+
+// Lists the difficulty names that every selected map would have at each rate in the rates input, so that invalid rates
+// and rates that would overwrite each other (i.e. "1.2" and "120%") show up before generating anything.
+fn preview_diff_names(data: &AppData) -> String {
+    let rates = match parse_rates(&data.rates_str) {
+        Ok(rates) => rates,
+        Err(e) => return format!("Generated difficulties: ({})", e),
+    };
+    let lines = data.files.iter().map(|map| {
+        let file_name = map.path.file_name().unwrap().to_string_lossy().trim_end_matches(".osu").to_string();
+        let diff_name = match &map.diff_name {
+            Ok(diff_name) => diff_name,
+            Err(e) => return format!("{}: {}", file_name, e),
+        };
+        let names = rates.iter().map(|rate| format!("[{} ({}x)]", diff_name, rate)).collect::<Vec<_>>();
+        let names = names.iter().enumerate()
+            .map(|(i, name)| if names[..i].contains(name) { format!("{} (duplicate)", name) } else { name.clone() });
+        format!("{}: {}", file_name, names.collect::<Vec<_>>().join(", "))
+    });
+    let lines = lines.collect::<Vec<_>>().join("\n");
+    format!("Generated difficulties:\n{}", if lines.is_empty() { "(none)" } else { lines.as_str() })
+}
+
+// Sent by the thread generating rates (see `generate`) to add a message to the log, and once it's done.
+const LOG_MESSAGE: Selector<String> = Selector::new("osurate.log-message");
+const GENERATION_FINISHED: Selector = Selector::new("osurate.generation-finished");
+
+// Generates the rates in the rates input for every selected map. This is done on another thread so that the UI stays
+// responsive, which sends its messages back to the UI thread through `sink` (see `Delegate`).
+fn generate(data: &mut AppData, sink: ExtEventSink) {
+    if data.generating {
+        data.log.push("[Info] rates are already being generated".to_string());
+        return;
+    }
+    let rates = match parse_rates(&data.rates_str) {
+        Ok(rates) => rates,
+        Err(e) => {
+            data.log.push(format!("[Error] {}", e));
+            return;
+        }
+    };
+
+    Settings::save(data);
+    data.generating = true;
+    let paths = data.files.iter().map(|f| f.path.clone()).collect::<Vec<_>>();
+    let options = Options { output_dir: data.output_dir.clone(), ..Default::default() };
+    thread::spawn(move || {
+        // This only fails if the window was closed, in which case there's nowhere to log to anyway.
+        let log = |message: String| {
+            let _ = sink.submit_command(LOG_MESSAGE, message, Target::Auto);
+        };
+
+        // Press on after encountering errors.
+        let rates = Rates::Fixed(rates);
+        let (mut n_generated, mut audio_size) = (0, 0);
+        let failed_rates = Mutex::new(vec![]);
+        let progress = |progress: Progress| {
+            if let Progress::RateFailed { map_name, rate, error } = progress {
+                let message = format!("[Error] couldn't generate {}x rate of {}: {}", rate, map_name, error);
+                failed_rates.lock().unwrap().push(message);
+            }
+        };
+        for path in &paths {
+            log(match generate_rates(path, &rates, &options, &progress) {
+                Err(e) => format!("[Error] {}", e),
+                Ok(generated) => {
+                    // Stretched audio takes up most of the space used by rates, so the total size of it is reported.
+                    n_generated += generated.len();
+                    let audio_paths = generated.iter().filter_map(|g| g.audio_path.as_ref());
+                    audio_size += audio_paths.filter_map(|p| fs::metadata(p).ok()).map(|m| m.len()).sum::<u64>();
+                    let rates = generated.iter().map(|g| format!("{}x: {}", g.rate, g.file_names()));
+                    format!("[Info] generated {}", rates.collect::<Vec<_>>().join("; "))
+                }
+            });
+            failed_rates.lock().unwrap().drain(..).for_each(&log);
+        }
+        if n_generated > 0 {
+            log(format!("[Info] generated {} rate(s), {} of audio", n_generated, format_size(audio_size)));
+        }
+        let _ = sink.submit_command(GENERATION_FINISHED, (), Target::Auto);
+    });
+}
+
+// Formats a file size given in bytes in megabytes (i.e. "42.3 MB").
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / 1_000_000.)
+}
+
+// Generates rates when Enter is pressed anywhere in the window, as if the "Generate" button was clicked.
+struct GenerateOnEnter;
+
+impl<W: Widget<AppData>> Controller<AppData, W> for GenerateOnEnter {
+    fn event(&mut self, child: &mut W, ctx: &mut EventCtx, event: &Event, data: &mut AppData, env: &Env) {
+        match event {
+            Event::KeyDown(key) if key.key == KbKey::Enter => {
+                generate(data, ctx.get_external_handle());
+                ctx.set_handled();
+            }
+            _ => child.event(ctx, event, data, env),
+        }
+    }
 }
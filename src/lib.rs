@@ -0,0 +1,9 @@
+// The beatmap and audio handling behind osurate, and the generation of rates from it, usable on its own (i.e. by the
+// fuzz targets in `fuzz/`).
+pub mod audio;
+pub mod beatmap;
+pub mod generate;
+pub mod util;
+
+#[cfg(test)]
+mod test_util;
@@ -1,24 +1,37 @@
-#![feature(available_concurrency)]
-#![feature(iter_intersperse)]
-#![feature(try_trait)]
-
-use std::fs::File;
-use std::io::{BufReader, Write};
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::path::Path;
+#[cfg(feature = "gui")]
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
 
 use clap::clap_app;
+use osurate::{audio, util};
+use osurate::beatmap::RateOptions;
+#[cfg(feature = "gui")]
+use osurate::generate::beatmap_extension;
+#[cfg(feature = "serde")]
+use osurate::generate::GeneratedRate;
+use osurate::generate::{
+    audio_stretcher, generate_batch, read_beatmap, verify_file_name_format, Error, Failure, Options, Progress, Rates,
+    CANCELLED, EXIT_BEATMAP, EXIT_CANCELLED, MIN_RATE,
+};
+use rayon::ThreadPoolBuilder;
 
-use crate::audio::AudioStretchError;
-use crate::beatmap::{Beatmap, ParseError};
-
-mod audio;
-mod beatmap;
 mod gui;
-mod util;
+#[cfg(test)]
+mod test_util;
 
 fn main() {
+    // Without rates, the CLI can't do anything with maps it's launched with on their own, so they're opened in the GUI.
+    #[cfg(feature = "gui")]
+    if let Some(files) = opened_files() {
+        gui::run_gui(files);
+    }
+
     // Change help text if compiled without GUI support.
-    let mut gui_help = "enters gui mode".to_string();
+    let mut gui_help = "enters gui mode, with the input .osu file(s) already selected".to_string();
     if !cfg!(feature = "gui") {
         gui_help += " (unavailable; recompile with `--features gui`)"
     }
@@ -27,76 +40,469 @@ fn main() {
         (version: "0.2.1")
         (author: "LunarCoffee <lunarcoffee.pjc@gmail.com>")
         (about: "rate generator for osu! beatmaps")
-        (@arg gui: -g conflicts_with[inputs rates] required_unless[inputs] gui_help)
-        (@arg inputs: #{1, u64::MAX} requires[rates] required_unless[gui] "sets the input .osu file(s)")
-        (@arg rates: -r #{1, u64::MAX} requires[inputs] "sets the rate(s) to generate")
+        (@arg gui: -g conflicts_with[rates bpm dump_json json] required_unless[inputs] gui_help)
+        (@arg inputs: #{1, u64::MAX} required_unless[gui] "sets the input .osu file(s)")
+        (@arg rates: -r #{1, u64::MAX} requires[inputs] required_unless[gui bpm dump_json]
+            "sets the rate(s) to generate (i.e. 1.2, 1.2x, 120%, or @file)")
+        (@arg bpm: -b --bpm +takes_value requires[inputs] conflicts_with[rates]
+            "generates the rate that brings each map's main bpm to this bpm")
+        (@arg min_rate: --("min-rate") +takes_value requires[inputs]
+            "sets the lowest rate allowed (0.01 by default), i.e. for experimenting with very slow rates")
+        (@arg dump_json: --("dump-json") requires[inputs] conflicts_with[rates bpm]
+            "prints each map as osurate parsed it, as json, without generating anything")
+        (@arg json: --json requires[inputs] conflicts_with[dump_json]
+            "prints the generated files and failures as a line of json at the end (i.e. for scripts)")
+        (@arg no_audio: --("no-audio") requires[inputs] "only generates beatmaps, leaving their audio unchanged")
+        (@arg rate_folders: --("rate-folders") requires[inputs]
+            "puts each rate in its own folder (i.e. rate_1.2) next to the original map")
+        (@arg preview_only: --("preview-only") requires[inputs]
+            conflicts_with[no_audio rate_folders filename_format]
+            "only changes the preview time of each map by the rate, overwriting it")
+        (@arg keep_name: --("keep-name") requires[inputs]
+            "leaves difficulty names as they are, instead of adding the rate to them")
+        (@arg exact_preview: --("exact-preview") requires[inputs]
+            "scales preview times without compensating for the delay of the stretched audio")
+        (@arg offset: --("offset-ms") +takes_value +allow_hyphen_values requires[inputs]
+            "adds an offset in milliseconds to every changed time (i.e. for audio stretched with another encoder)")
+        (@arg pitch: --pitch +takes_value +allow_hyphen_values requires[inputs] conflicts_with[no_audio preview_only]
+            "shifts the pitch of stretched audio by this many semitones, on top of the rate")
+        (@arg loudness_target: --("loudness-target") +takes_value +allow_hyphen_values requires[inputs]
+            conflicts_with[no_audio preview_only]
+            "matches the loudness of stretched audio to this many LUFS (i.e. -14), tagging it with its ReplayGain")
+        (@arg backend: --backend +takes_value requires[inputs] conflicts_with[no_audio preview_only]
+            "sets how audio is stretched, either natively or with ffmpeg (native by default)")
+        (@arg anti_alias: --("anti-alias") requires[inputs] conflicts_with[no_audio preview_only]
+            "low-pass filters audio before speeding it up, which keeps it from aliasing")
+        (@arg threads: --threads +takes_value requires[inputs]
+            "sets the number of threads used to resample audio (1 is the most accurate)")
+        (@arg jobs: -j --jobs +takes_value requires[inputs]
+            "sets the number of rates generated at once (1 by default)")
+        (@arg preserve_mtime: --("preserve-mtime") requires[inputs]
+            "gives generated files the modification time of the files they were generated from")
+        (@arg filename_format: --("filename-format") +takes_value requires[inputs]
+            "sets the output file name, where {name}, {diff}, and {rate} are replaced (i.e. \"{name} {rate}x.osu\")")
+        (@setting SubcommandsNegateReqs)
+        (@subcommand audio =>
+            (about: "stretches an mp3 file without a beatmap")
+            (@arg input: +required "sets the input .mp3 file")
+            (@arg rate: -r +takes_value +required "sets the rate to stretch the audio by")
+            (@arg min_rate: --("min-rate") +takes_value "sets the lowest rate allowed (0.01 by default)")
+            (@arg output: -o +takes_value +required "sets the output .mp3 file")
+            (@arg pitch: --pitch +takes_value +allow_hyphen_values "shifts the pitch by this many semitones")
+            (@arg loudness_target: --("loudness-target") +takes_value +allow_hyphen_values
+                "matches the loudness to this many LUFS (i.e. -14)")
+            (@arg backend: --backend +takes_value "sets how the audio is stretched, either natively or with ffmpeg")
+            (@arg anti_alias: --("anti-alias") "low-pass filters the audio before speeding it up")
+            (@arg threads: --threads +takes_value "sets the number of threads used to resample audio"))
+        (@subcommand info =>
+            (about: "prints a summary of beatmaps without generating anything")
+            (@arg inputs: #{1, u64::MAX} +required "sets the input .osu file(s)"))
+        (@subcommand check =>
+            (about: "checks that beatmaps can be rated, without generating anything (i.e. for ci)")
+            (@arg inputs: #{1, u64::MAX} +required "sets the input .osu file(s)"))
         (help_message: "prints help information")
         (version_message: "prints version information")
     ).get_matches();
 
-    if matches.is_present("gui") {
-        #[cfg(feature = "gui")] gui::run_gui(); // This call diverges.
+    if let Some(matches) = matches.subcommand_matches("audio") {
+        let min_rate = parse_min_rate_arg(matches.value_of("min_rate"));
+        let rate = util::parse_rate(matches.value_of("rate").unwrap());
+        let rate = rate.unwrap_or_else(|| util::log_fatal("invalid rate specified"));
+        check_min_rate(&[rate], min_rate);
+        let (input, output) = (matches.value_of("input").unwrap(), matches.value_of("output").unwrap());
+        let n_threads = parse_count_arg(matches.value_of("threads"), "thread count");
+        let semitones = parse_pitch_arg(matches.value_of("pitch"));
+        let loudness_target = parse_loudness_target_arg(matches.value_of("loudness_target"));
+        let anti_alias = matches.is_present("anti_alias");
+        let native = audio::Mp3Stretcher { n_threads, semitones, loudness_target, anti_alias };
+        let stretcher = audio_stretcher(parse_backend_arg(matches.value_of("backend"), loudness_target), native);
+
+        util::log_info("starting...");
+        if let Err(e) = audio::stretch_file(Path::new(input), Path::new(output), rate, &*stretcher) {
+            let e = Error::from(e);
+            util::log_error(&e);
+            process::exit(e.exit_code);
+        }
+        util::log_info(format!("generated {}x rate of {}", rate, input));
+    } else if let Some(matches) = matches.subcommand_matches("info") {
+        let mut failed = false;
+        for path in matches.values_of("inputs").unwrap().map(Path::new) {
+            if let Err(e) = print_info(path) {
+                util::log_error(format!("{}: {}", path.display(), e));
+                failed = true;
+            }
+        }
+        if failed {
+            process::exit(EXIT_BEATMAP);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("check") {
+        let mut failed = false;
+        for path in matches.values_of("inputs").unwrap().map(Path::new) {
+            match check_beatmap(path) {
+                Ok(summary) => util::log_info(format!("{}: ok ({})", path.display(), summary)),
+                Err(e) => {
+                    util::log_error(format!("{}: {}", path.display(), e));
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            process::exit(EXIT_BEATMAP);
+        }
+    } else if matches.is_present("gui") {
+        // The inputs are only selected, so that rates can be entered for them (i.e. when opening maps with osurate).
+        #[cfg(feature = "gui")] {
+            let files = matches.values_of_os("inputs").into_iter().flatten().map(PathBuf::from).collect();
+            gui::run_gui(files); // This call diverges.
+        }
         util::log_fatal("osurate was not compiled with gui support; recompile with `--features gui`");
+    } else if matches.is_present("dump_json") {
+        let mut failed = false;
+        for path in matches.values_of("inputs").unwrap().map(Path::new) {
+            if let Err(e) = dump_json(path) {
+                util::log_error(format!("{}: {}", path.display(), e));
+                failed = true;
+            }
+        }
+        if failed {
+            process::exit(EXIT_BEATMAP);
+        }
     } else {
-        let rate_matches = matches.values_of("rates").unwrap();
         let map_paths = matches.values_of("inputs").unwrap();
+        let min_rate = parse_min_rate_arg(matches.value_of("min_rate"));
 
-        let rates = rate_matches.map(|r| r.parse::<f64>()).collect::<Result<Vec<_>, _>>()
-            .unwrap_or_else(|_| util::log_fatal("invalid rate(s) specified"));
-        rates.iter().any(|&r| r < 0.01).then(|| util::log_fatal("rates below 0.01 are not supported"));
+        let rates = if let Some(bpm) = matches.value_of("bpm") {
+            let bpm = bpm.parse::<f64>().ok().filter(|&b| b > 0. && b.is_finite());
+            Rates::TargetBpm(bpm.unwrap_or_else(|| util::log_fatal("invalid bpm specified")))
+        } else {
+            let rate_matches = matches.values_of("rates").unwrap();
+            let rates = rate_matches.map(read_rate_arg).collect::<Result<Vec<_>, _>>();
+            let rates = rates.unwrap_or_else(|e| util::log_fatal(e)).concat();
+            let rates = rates.iter().map(|r| util::parse_rate(r)).collect::<Option<Vec<_>>>()
+                .unwrap_or_else(|| util::log_fatal("invalid rate(s) specified"));
+            check_min_rate(&rates, min_rate);
 
+            // Since the map is overwritten, more than one rate would just compound.
+            if matches.is_present("preview_only") && rates.len() > 1 {
+                util::log_fatal("only one rate can be used with --preview-only");
+            }
+            Rates::Fixed(rates)
+        };
+
+        let json = matches.is_present("json");
+        if json && !cfg!(feature = "serde") {
+            util::log_fatal("osurate was not compiled with json support; recompile with `--features serde`");
+        }
+
+        let filename_format = matches.value_of("filename_format").map(str::to_string);
+        if let Some(format) = &filename_format {
+            verify_file_name_format(format).unwrap_or_else(|e| util::log_fatal(e));
+        }
+
+        let loudness_target = parse_loudness_target_arg(matches.value_of("loudness_target"));
+        let options = Options {
+            no_audio: matches.is_present("no_audio"),
+            rate_folders: matches.is_present("rate_folders"),
+            filename_format,
+            output_dir: None,
+            min_rate,
+            threads: parse_count_arg(matches.value_of("threads"), "thread count"),
+            jobs: parse_count_arg(matches.value_of("jobs"), "job count").unwrap_or(1),
+            pitch: parse_pitch_arg(matches.value_of("pitch")),
+            loudness_target,
+            anti_alias: matches.is_present("anti_alias"),
+            ffmpeg: parse_backend_arg(matches.value_of("backend"), loudness_target),
+            preview_only: matches.is_present("preview_only"),
+            preserve_mtime: matches.is_present("preserve_mtime"),
+            rate_options: RateOptions {
+                exact_preview_time: matches.is_present("exact_preview"),
+                offset: matches.value_of("offset").map_or(Some(0.), |o| o.parse().ok().filter(|o: &f64| o.is_finite()))
+                    .unwrap_or_else(|| util::log_fatal("invalid offset specified")),
+                keep_diff_name: matches.is_present("keep_name"),
+            },
+        };
+
+        // Every job gets its own thread, which is shared between the maps and their rates (see `generate_batch`).
+        let pool = ThreadPoolBuilder::new().num_threads(options.jobs).build();
+        let pool = pool.unwrap_or_else(|_| util::log_fatal("couldn't start worker threads"));
+
+        // The first Ctrl-C skips the rates that haven't been started yet, and lets the ones being generated finish, so
+        // that no temporary files are left behind (see `util::write_atomically`). The second one exits right away, so
+        // the temporary (".{name}.*.tmp") files of the rates being generated are left behind, next to where they would
+        // have been saved. They're never loaded by osu! (which only looks for .osu files), and can be deleted.
+        let handler = ctrlc::set_handler(|| {
+            if CANCELLED.swap(true, Ordering::SeqCst) {
+                process::exit(EXIT_CANCELLED);
+            }
+            util::log_warn("stopping after the rates being generated; press ctrl-c again to stop right away");
+        });
+        if handler.is_err() {
+            util::log_warn("couldn't set up ctrl-c handling");
+        }
+
+        // Like the GUI, press on after encountering errors (in maps as a whole, or in single rates of them), and report
+        // them at the end.
         util::log_info("starting...");
-        for path in map_paths.map(|p| Path::new(p)) {
-            if let Err(e) = generate_rates(&path.to_path_buf(), &rates) {
-                util::log_fatal(e);
+        let paths = map_paths.map(Path::new).collect::<Vec<_>>();
+        let failures = Mutex::new(vec![]);
+        let progress = |path, progress: Progress| {
+            if let Progress::RateFailed { rate, error, .. } = progress {
+                failures.lock().unwrap().push(Failure { path, rate: Some(rate), error: error.clone() });
             }
+            log_progress(progress);
+        };
+        let results = pool.install(|| generate_batch(&paths, &rates, &options, &progress));
+        let mut failures = failures.into_inner().unwrap();
+        for (&path, result) in paths.iter().zip(&results) {
+            match result {
+                Err(e) if e.exit_code != EXIT_CANCELLED => {
+                    let failure = Failure { path, rate: None, error: e.clone() };
+                    util::log_error(&failure);
+                    failures.push(failure);
+                }
+                _ => {}
+            }
+        }
+
+        let generated = paths.iter().zip(&results).filter_map(|(&path, result)| Some((path, result.as_ref().ok()?)));
+        let generated = generated.collect::<Vec<_>>();
+        let n_rates = generated.iter().map(|(_, generated)| generated.len()).sum::<usize>();
+
+        // Errors are repeated here so that they aren't lost among the rest of the output.
+        let n_failures = failures.len();
+        util::log_info(format!("{} map(s), {} rate(s) generated, {} failure(s)", paths.len(), n_rates, n_failures));
+        for failure in &failures {
+            util::log_error(failure);
+        }
+        let cancelled = CANCELLED.load(Ordering::SeqCst);
+        #[cfg(feature = "serde")]
+        if json {
+            println!("{}", json_summary(&generated, &failures, cancelled));
+        }
+        if cancelled {
+            util::log_warn("stopped early; only the rates listed above were generated");
+            process::exit(EXIT_CANCELLED);
+        } else if let Some(failure) = failures.first() {
+            // If maps failed for different reasons, the first one decides the exit code.
+            process::exit(failure.error.exit_code);
+        }
+    }
+}
+
+// Returns the maps that osurate was launched with if there are no other arguments (i.e. because an operating system
+// opened them with osurate, when double-clicking a map that is associated with it).
+#[cfg(feature = "gui")]
+fn opened_files() -> Option<Vec<PathBuf>> {
+    let args = std::env::args_os().skip(1).map(PathBuf::from).collect::<Vec<_>>();
+    let is_map = |p: &PathBuf| p.is_file() && beatmap_extension(p).filter(|e| e.eq_ignore_ascii_case("osu")).is_some();
+    Some(args).filter(|args| !args.is_empty() && args.iter().all(is_map))
+}
+
+// Returns the rate(s) given by a rate argument, which is either a rate or a '@' followed by the path of a file to read
+// rates from.
+fn read_rate_arg(arg: &str) -> Result<Vec<String>, String> {
+    match arg.strip_prefix('@') {
+        Some(path) => {
+            let list = fs::read_to_string(path).map_err(|_| format!("couldn't read rates file {}", path))?;
+            Ok(util::split_rate_list(&list).into_iter().map(str::to_string).collect())
+        }
+        _ => Ok(vec![arg.to_string()]),
+    }
+}
+
+// Parses the value of `--min-rate`, exiting if it isn't a positive rate. The default is `MIN_RATE`.
+fn parse_min_rate_arg(arg: Option<&str>) -> f64 {
+    let min_rate = arg.map_or(Some(MIN_RATE), util::parse_rate);
+    min_rate.unwrap_or_else(|| util::log_fatal("invalid minimum rate specified"))
+}
+
+// Exits if any of `rates` are below `min_rate`, and warns if any are below `MIN_RATE` (so `--min-rate` was used).
+fn check_min_rate(rates: &[f64], min_rate: f64) {
+    if rates.iter().any(|&r| r < min_rate) {
+        util::log_fatal(format!("rates below {} are not supported (see --min-rate)", min_rate));
+    } else if rates.iter().any(|&r| r < MIN_RATE) {
+        util::log_warn(format!("rates below {} may take a lot of time, memory, and disk space", MIN_RATE));
+    }
+}
+
+// Parses the value of an argument like `--threads`, exiting if it isn't a positive integer. `what` describes the value
+// in the error message.
+fn parse_count_arg(arg: Option<&str>, what: &str) -> Option<usize> {
+    let count = arg.map(|c| c.parse::<usize>().ok().filter(|&c| c > 0));
+    count.map(|c| c.unwrap_or_else(|| util::log_fatal(format!("invalid {} specified", what))))
+}
+
+// Parses the value of `--pitch` in semitones, exiting if it isn't a finite number. The pitch is left alone if `None`.
+fn parse_pitch_arg(arg: Option<&str>) -> f64 {
+    let pitch = arg.map_or(Some(0.), |p| p.parse().ok().filter(|p: &f64| p.is_finite()));
+    pitch.unwrap_or_else(|| util::log_fatal("invalid pitch specified"))
+}
+
+// Parses the value of `--loudness-target` in LUFS, exiting if it isn't a negative number.
+fn parse_loudness_target_arg(arg: Option<&str>) -> Option<f64> {
+    let target = arg.map(|t| t.parse().ok().filter(|t: &f64| *t < 0. && t.is_finite()));
+    target.map(|t| t.unwrap_or_else(|| util::log_fatal("invalid loudness target specified")))
+}
+
+// Parses the value of `--backend`, returning whether ffmpeg should be used, and exiting if it isn't a known backend or
+// can't be used with `loudness_target`.
+fn parse_backend_arg(arg: Option<&str>, loudness_target: Option<f64>) -> bool {
+    match arg {
+        None | Some("native") => false,
+        Some("ffmpeg") if !cfg!(feature = "ffmpeg") => {
+            util::log_fatal("osurate was not compiled with ffmpeg support; recompile with `--features ffmpeg`")
         }
+        Some("ffmpeg") if loudness_target.is_some() => util::log_fatal("--loudness-target can't be used with ffmpeg"),
+        Some("ffmpeg") => true,
+        Some(_) => util::log_fatal("invalid backend specified; use either `native` or `ffmpeg`"),
     }
 }
 
-// Generates and saves the rates in `rates` for the .osu file at `path`. The returned value is the name of the map,
-// used for user-facing logging.
-fn generate_rates(path: &PathBuf, rates: &[f64]) -> Result<String, String> {
-    let path = path.canonicalize().map_err(|_| "couldn't find file")?;
-    let base_map_name = path.file_stem().ok_or_else(|| "not a file").map(|s| s.to_string_lossy())?;
-    let map_file = File::open(&path).map_err(|_| "couldn't open file")?;
-    let reader = BufReader::new(map_file);
-
-    let map = Beatmap::parse(reader).map_err(|e| match e {
-        ParseError::UnsupportedVersion => "unsupported beatmap file format version",
-        ParseError::InvalidBeatmap => "couldn't parse beatmap file",
-        _ => "beatmap file i/o error",
-    })?;
-
-    for rate in rates {
-        // Since the map is mutated by `change_rate`, inaccuracies may accumulate when reverting a rate change. To work
-        // around this, the beatmap is cloned for each rate.
-        generate_rate(map.clone(), *rate, &path)?;
-        util::log_info(format!("generated {}x rate of {}", rate, base_map_name));
+// Logs the progress of the CLI.
+fn log_progress(progress: Progress) {
+    match progress {
+        Progress::ParsedMap { map_name, n_rates } => {
+            util::log_info(format!("generating {} rate(s) of {}", n_rates, map_name));
+        }
+        Progress::StretchedAudio { map_name, rate } => {
+            util::log_info(format!("stretched audio for {}x rate of {}", rate, map_name));
+        }
+        Progress::WroteMap { map_name, generated } => {
+            let (rate, file_names) = (generated.rate, generated.file_names());
+            util::log_info(format!("generated {}x rate of {} ({})", rate, map_name, file_names));
+            util::log_info(format!("new length: {}", util::format_duration(generated.length)));
+        }
+        Progress::RateFailed { map_name, rate, error } => {
+            util::log_error(format!("couldn't generate {}x rate of {}: {}", rate, map_name, error));
+        }
     }
-    Ok(base_map_name.to_string())
-}
-
-// Generates and saves the given rate for the given beatmap.
-fn generate_rate(mut map: Beatmap, rate: f64, path: &PathBuf) -> Result<(), String> {
-    let parent_dir = path.parent().unwrap_or(Path::new("./"));
-
-    map.change_rate(rate).then(|| {}).ok_or_else(|| "invalid beatmap file")?;
-    audio::stretch_beatmap_audio(&mut map, parent_dir, rate).map_err(|e| match e {
-        AudioStretchError::SourceNotFound => "couldn't find mp3 file",
-        AudioStretchError::InvalidSource => "couldn't parse mp3 file",
-        AudioStretchError::UnsupportedChannelCount => "unsupported mp3 channel count",
-        AudioStretchError::LameInitializationError => "couldn't initialize lame (is it installed?)",
-        AudioStretchError::LameEncodingError => "lame mp3 encoding error",
-        _ => "mp3 output i/o error",
-    })?;
-
-    // New file name with the rate in the difficulty name part.
-    let old_file_name = path.file_stem().unwrap().to_string_lossy();
-    let name_with_rate = format!("{} ({}x)].osu", &old_file_name[..old_file_name.len() - 1], rate);
-
-    let new_path = parent_dir.join(name_with_rate);
-    let mut new_file = File::create(new_path).map_err(|_| "couldn't create new beatmap file")?;
-    new_file.write_all(map.into_string().as_bytes()).map_err(|_| "couldn't write new beatmap file")?;
+}
+
+// Prints a summary of the beatmap at `path` (see the `info` subcommand).
+fn print_info(path: &Path) -> Result<(), String> {
+    let map = read_beatmap(path)?;
+    let value = |key| map.metadata.value(key).unwrap_or_default();
+    let round = |bpm: f64| (bpm * 1000.).round() / 1000.;
+    let (min_bpm, max_bpm) = map.bpm_range().ok_or("beatmap has no uninherited timing points")?;
+    let (min_bpm, max_bpm) = (round(min_bpm), round(max_bpm));
+    let n_uninherited = map.timing_points.iter().filter(|p| p.beat_len.is_sign_positive()).count();
+
+    println!("{}", path.display());
+    println!("  title: {} - {} ({})", value("Artist"), value("Title"), value("Creator"));
+    println!("  difficulty: {}", map.metadata.diff_name);
+    if min_bpm == max_bpm {
+        println!("  bpm: {}", min_bpm);
+    } else {
+        println!("  bpm: {}-{} ({} uninherited timing points)", min_bpm, max_bpm, n_uninherited);
+    }
+    println!("  objects: {}", map.hit_objects.len());
+    println!("  length: {}", util::format_duration(map.length()));
+    println!("  audio: {}", map.general_info.audio_file);
     Ok(())
 }
+
+// Checks that the beatmap at `path` can be rated (see the `check` subcommand), returning a short summary of it. This
+// fails for anything that would keep rates from being generated, and warns about missing audio, since rates can still
+// be generated without it with `--no-audio`.
+fn check_beatmap(path: &Path) -> Result<String, String> {
+    let map = read_beatmap(path)?;
+    if let Some(rate) = map.rate_suffix() {
+        return Err(format!("\"{}\" is already a {}x rate", map.metadata.diff_name, rate));
+    }
+    let parent_dir = path.parent().unwrap_or_else(|| Path::new("./"));
+    if !audio::audio_source_path(parent_dir, &map.general_info.audio_file).is_file() {
+        let (audio_file, hint) = (&map.general_info.audio_file, "rates can only be generated with --no-audio");
+        util::log_warn(format!("{}: audio file {} not found; {}", path.display(), audio_file, hint));
+    }
+    Ok(format!("osu file format v{}, {} hit objects", map.format_version, map.hit_objects.len()))
+}
+
+// Describes the files generated for each map, and the maps and rates that failed, as JSON (see `--json`). Rates that
+// failed as part of a map that failed as a whole have a `null` rate.
+#[cfg(feature = "serde")]
+fn json_summary(
+    generated: &[(&Path, &Vec<GeneratedRate>)],
+    failures: &[Failure],
+    cancelled: bool,
+) -> serde_json::Value {
+    let generated = generated.iter().flat_map(|(path, generated)| {
+        generated.iter().map(move |g| {
+            let outputs = g.paths().map(|p| p.to_string_lossy()).collect::<Vec<_>>();
+            serde_json::json!({ "path": path.to_string_lossy(), "rate": g.rate, "outputs": outputs })
+        })
+    });
+    let failed = failures.iter().map(|f| {
+        let (path, message) = (f.path.to_string_lossy(), &f.error.message);
+        serde_json::json!({ "path": path, "rate": f.rate, "error": message, "exit_code": f.error.exit_code })
+    });
+    let (generated, failed) = (generated.collect::<Vec<_>>(), failed.collect::<Vec<_>>());
+    serde_json::json!({ "generated": generated, "failed": failed, "cancelled": cancelled })
+}
+
+// Prints the beatmap at `path` as pretty JSON, in osurate's representation (see `--dump-json`).
+#[cfg(feature = "serde")]
+fn dump_json(path: &Path) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(&read_beatmap(path)?).map_err(|e| e.to_string())?;
+    println!("{}", json);
+    Ok(())
+}
+
+#[cfg(not(feature = "serde"))]
+fn dump_json(_: &Path) -> Result<(), String> {
+    util::log_fatal("osurate was not compiled with json support; recompile with `--features serde`")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::check_beatmap;
+    use crate::test_util::{fixture, TestDir};
+
+    #[test]
+    fn maps_are_checked_without_generating_anything() {
+        let dir = TestDir::new("maps_are_checked_without_generating_anything");
+        let source = &fixture("standard");
+        fs::write(dir.join("map.osu"), source).unwrap();
+        let rated = source.replace("Version:Annihilation", "Version:Annihilation (1.2x)");
+        fs::write(dir.join("rated.osu"), rated).unwrap();
+        fs::write(dir.join("unsupported.osu"), source.replace("osu file format v14", "osu file format v3")).unwrap();
+
+        assert_eq!(check_beatmap(&dir.join("map.osu")).unwrap(), "osu file format v14, 6 hit objects");
+        assert!(check_beatmap(&dir.join("rated.osu")).unwrap_err().contains("already a 1.2x rate"));
+        assert!(check_beatmap(&dir.join("unsupported.osu")).unwrap_err().contains("unsupported"));
+        assert!(check_beatmap(&dir.join("missing.osu")).is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_summary_lists_generated_files_and_failures() {
+        use std::path::{Path, PathBuf};
+
+        use osurate::generate::{Error, Failure, GeneratedRate, EXIT_AUDIO_SOURCE, EXIT_BEATMAP};
+
+        use crate::json_summary;
+
+        let generated = vec![GeneratedRate {
+            rate: 1.2,
+            length: 0.,
+            map_path: PathBuf::from("a (1.2x).osu"),
+            audio_path: Some(PathBuf::from("audio_1.2.mp3")),
+            storyboard_path: None,
+        }];
+        let failures = [
+            Failure { path: Path::new("a.osu"), rate: Some(1.1), error: Error::new(EXIT_AUDIO_SOURCE, "no audio") },
+            Failure { path: Path::new("b.osu"), rate: None, error: Error::new(EXIT_BEATMAP, "couldn't open file") },
+        ];
+        let summary = json_summary(&[(Path::new("a.osu"), &generated)], &failures, false);
+        assert_eq!(summary, serde_json::json!({
+            "generated": [{ "path": "a.osu", "rate": 1.2, "outputs": ["a (1.2x).osu", "audio_1.2.mp3"] }],
+            "failed": [
+                { "path": "a.osu", "rate": 1.1, "error": "no audio", "exit_code": EXIT_AUDIO_SOURCE },
+                { "path": "b.osu", "rate": null, "error": "couldn't open file", "exit_code": EXIT_BEATMAP },
+            ],
+            "cancelled": false,
+        }));
+    }
+}
@@ -0,0 +1,46 @@
+// Helpers shared by the unit tests of both the library and the binary, which is why this doesn't depend on anything
+// else in the crate.
+
+use std::{env, fs};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+// A directory for the files of a single test, named after `name` in the system's temporary directory. It's emptied
+// when it's created, in case an earlier run was killed before cleaning up, and removed when it's dropped, even if the
+// test panics.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("osurate_{}", name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Reads the fixture named `name` (without its extension) in `tests/fixtures`.
+pub fn fixture(name: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name).with_extension("osu");
+    fs::read_to_string(path).unwrap()
+}
@@ -1,17 +1,253 @@
 use std::fmt::Display;
-use std::process;
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::{fs, io, process, thread};
 
 // Returns a result based on whether `cond` is true. This is designed to be used with the ? operator, returning Err(e)
 // when `cond` is false, and Ok(()) otherwise.
 pub fn verify<E>(cond: bool, e: E) -> Result<(), E> {
-    cond.then(|| {}).ok_or(e)
+    cond.then_some(()).ok_or(e)
+}
+
+// Parses a rate given either as a multiplier (i.e. "1.5", or "1.5x" as shown in osu!) or a percentage (i.e. "150%").
+// Only positive, finite rates are accepted, so "NaN", "inf", "0x", and the like aren't rates.
+pub fn parse_rate(rate: &str) -> Option<f64> {
+    let rate = rate.trim();
+    let rate = match rate.strip_suffix('%') {
+        Some(percentage) => percentage.trim_end().parse::<f64>().ok().map(|p| p / 100.),
+        _ => rate.strip_suffix(&['x', 'X'][..]).map_or(rate, str::trim_end).parse().ok(),
+    };
+    rate.filter(|&r| r > 0. && r.is_finite())
+}
+
+// Splits a list of rates separated by commas or newlines, ignoring blank lines and comments starting with '#'.
+pub fn split_rate_list(list: &str) -> Vec<&str> {
+    let lines = list.lines().map(|line| line.split('#').next().unwrap());
+    lines.flat_map(|line| line.split(',')).map(str::trim).filter(|r| !r.is_empty()).collect()
+}
+
+// Decodes UTF-16 text starting with a byte order mark, which determines whether it's little or big endian. The byte
+// order mark itself is removed. Returns `None` if there is no byte order mark or the text isn't valid UTF-16.
+pub fn decode_utf16(data: &[u8]) -> Option<String> {
+    let from_bytes = match data.get(..2)? {
+        [0xff, 0xfe] => u16::from_le_bytes,
+        [0xfe, 0xff] => u16::from_be_bytes,
+        _ => return None,
+    };
+    let chunks = data[2..].chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    String::from_utf16(&chunks.map(|c| from_bytes([c[0], c[1]])).collect::<Vec<_>>()).ok()
+}
+
+// Formats a duration given in milliseconds as minutes and seconds (i.e. "2:14").
+pub fn format_duration(ms: f64) -> String {
+    let seconds = (ms / 1000.).max(0.) as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+// Replaces characters that aren't allowed in file names on some platforms (namely Windows) with underscores.
+pub fn sanitize_file_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_control() || r#"<>:"/\|?*"#.contains(c) { '_' } else { c }).collect()
+}
+
+// Checks if `a` and `b` refer to the same file, even if one doesn't exist (yet).
+pub fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// Counts the temporary files created by `write_atomically`.
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Writes a file to `path` with `write` without ever leaving a partially written file there. The data is written to a
+// temporary file in the same directory, which is renamed into place on success and removed otherwise. Only creating
+// and renaming the file are retried with `retry_io`, since those are what a briefly locked file or folder makes fail;
+// `write` runs once, and its errors are returned right away. Any I/O errors are reported as `io_error`, and the value
+// returned by `write` is passed through. If the process exits while `write` is running, the temporary file is left
+// behind.
+pub fn write_atomically<T, E>(
+    path: &Path,
+    io_error: E,
+    write: impl FnOnce(&mut File) -> Result<T, E>,
+) -> Result<T, E> {
+    // The temporary file is named after the process and a counter too, so that writers of the same file never write to
+    // the same temporary file.
+    let file_name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let n = TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let temp_path = path.with_file_name(format!(".{}.{}.{}.tmp", file_name, process::id(), n));
+
+    let result = match retry_io(&temp_path, || File::create(&temp_path)) {
+        Ok(mut file) => write(&mut file).and_then(|value| {
+            // The file must be closed before it can be renamed on some platforms.
+            drop(file);
+            retry_io(path, || fs::rename(&temp_path, path)).or(Err(io_error)).map(|_| value)
+        }),
+        Err(_) => Err(io_error),
+    };
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// How many times `retry_io` retries an operation, and how long it waits before the first retry. The wait is doubled
+// after every retry.
+const IO_RETRIES: u32 = 4;
+const IO_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+// Runs the I/O operation `op` on the file at `path`, retrying it a few times if it fails with an error that may be
+// temporary (see `is_transient_io_error`). Files on network drives or in folders watched by antivirus software (like
+// the osu! Songs folder often is) can be briefly locked, for example.
+pub fn retry_io<T>(path: &Path, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = IO_RETRY_DELAY;
+    for _ in 0..IO_RETRIES {
+        match op() {
+            Err(e) if is_transient_io_error(&e) => {
+                log_info(format!("couldn't write {} ({}); retrying in {} ms", path.display(), e, delay.as_millis()));
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    op()
+}
+
+// Checks if an I/O error may go away by itself. Errors like a missing folder or a lack of permissions won't, so
+// they're reported right away. On Windows, a file that is open elsewhere causes a sharing or lock violation.
+fn is_transient_io_error(e: &io::Error) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+    let locked = cfg!(windows) && matches!(e.raw_os_error(), Some(ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION));
+    locked || matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
 }
 
 pub fn log_info<D: Display>(value: D) {
     println!("info: {}", value);
 }
 
-pub fn log_fatal<D: Display>(value: D) -> ! {
+pub fn log_warn<D: Display>(value: D) {
+    println!("warning: {}", value);
+}
+
+pub fn log_error<D: Display>(value: D) {
     eprintln!("error: {}", value);
+}
+
+pub fn log_fatal<D: Display>(value: D) -> ! {
+    log_error(value);
     process::exit(1)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::io::Write;
+
+    use crate::test_util::TestDir;
+    use crate::util;
+
+    #[test]
+    fn parse_rate_accepts_multipliers_and_percentages() {
+        assert_eq!(util::parse_rate("1.5"), Some(1.5));
+        assert_eq!(util::parse_rate(" 120% "), Some(1.2));
+        assert_eq!(util::parse_rate("85 %"), Some(0.85));
+        assert_eq!(util::parse_rate("%"), None);
+        assert_eq!(util::parse_rate("1.5%%"), None);
+    }
+
+    #[test]
+    fn parse_rate_rejects_rates_that_arent_positive_and_finite() {
+        for rate in ["NaN", "inf", "infx", "NaN%", "-inf%", "1e400%", "0", "0%", "-1.5x", "1e-400"].iter() {
+            assert_eq!(util::parse_rate(rate), None, "{}", rate);
+        }
+    }
+
+    #[test]
+    fn parse_rate_accepts_a_trailing_x() {
+        assert_eq!(util::parse_rate("1.5x"), Some(1.5));
+        assert_eq!(util::parse_rate(" 0.85 X "), Some(0.85));
+        assert_eq!(util::parse_rate("x"), None);
+        assert_eq!(util::parse_rate("1.5xx"), None);
+        assert_eq!(util::parse_rate("150%x"), None);
+    }
+
+    #[test]
+    fn split_rate_list_ignores_blank_lines_and_comments() {
+        let list = "# My usual rates.\n1.1, 1.2\n\n1.3 # For practice.\n120%,\n";
+        assert_eq!(util::split_rate_list(list), ["1.1", "1.2", "1.3", "120%"]);
+    }
+
+    #[test]
+    fn decode_utf16_handles_both_byte_orders() {
+        let text = "osu file format v14\r\n[General]\r\nAudioFilename: 音.mp3";
+        let le = text.encode_utf16().flat_map(u16::to_le_bytes);
+        let be = text.encode_utf16().flat_map(u16::to_be_bytes);
+        assert_eq!(util::decode_utf16(&[0xff, 0xfe].iter().copied().chain(le).collect::<Vec<_>>()).unwrap(), text);
+        assert_eq!(util::decode_utf16(&[0xfe, 0xff].iter().copied().chain(be).collect::<Vec<_>>()).unwrap(), text);
+        assert_eq!(util::decode_utf16(text.as_bytes()), None);
+        assert_eq!(util::decode_utf16(&[0xff, 0xfe, 0x41]), None); // An odd number of bytes.
+        assert_eq!(util::decode_utf16(&[0xff, 0xfe, 0x00, 0xd8]), None); // An unpaired surrogate.
+    }
+
+    #[test]
+    fn format_duration_uses_minutes_and_seconds() {
+        assert_eq!(util::format_duration(134_900.), "2:14");
+        assert_eq!(util::format_duration(5_000.), "0:05");
+        assert_eq!(util::format_duration(-75.), "0:00");
+        assert_eq!(util::format_duration(3_723_000.), "62:03");
+    }
+
+    #[test]
+    fn sanitize_file_name_replaces_illegal_characters() {
+        assert_eq!(util::sanitize_file_name("a/b\\c: \"d\" <e>|f?*.osu"), "a_b_c_ _d_ _e__f__.osu");
+        assert_eq!(util::sanitize_file_name("Artist - Title [Diff (1.2x)].osu"), "Artist - Title [Diff (1.2x)].osu");
+    }
+
+    #[test]
+    fn is_same_file_resolves_paths() {
+        let dir = std::env::temp_dir();
+        assert!(util::is_same_file(&dir.join("audio.mp3"), &dir.join(".").join("audio.mp3")));
+        assert!(!util::is_same_file(&dir.join("audio.mp3"), &dir.join("audio_1_2.mp3")));
+    }
+
+    #[test]
+    fn retry_io_only_retries_transient_errors() {
+        let path = std::env::temp_dir().join("osurate_retry_io.osu");
+        let mut attempts = 0;
+        let result = util::retry_io(&path, || {
+            attempts += 1;
+            if attempts < 3 { Err(io::Error::from(io::ErrorKind::Interrupted)) } else { Ok(attempts) }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut attempts = 0;
+        let result: io::Result<()> = util::retry_io(&path, || {
+            attempts += 1;
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        });
+        assert!(result.is_err() && attempts == 1);
+    }
+
+    #[test]
+    fn write_atomically_leaves_nothing_behind_on_error() {
+        let dir = TestDir::new("write_atomically");
+
+        let path = dir.join("ok.osu");
+        util::write_atomically(&path, (), |f| f.write_all(b"data").or(Err(()))).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+
+        let path = dir.join("failed.osu");
+        let result = util::write_atomically(&path, (), |f| f.write_all(b"partial").map_or(Err(()), |_| Err(())));
+        let result: Result<(), _> = result;
+        assert!(result.is_err() && !path.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,125 @@
+// Fixture based tests for changing the rate of whole beatmaps. Each fixture in `tests/fixtures` is rated at a couple of
+// rates, and the output is compared against the expected output in `tests/fixtures/expected`, which is named after the
+// fixture and the rate (i.e. "standard_1.5.osu"). After an intentional change to the output, the expected outputs can
+// be regenerated by running these tests with `OSURATE_BLESS=1` set.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use osurate::beatmap::{Beatmap, HitObjectParams, RateOptions};
+
+const FIXTURES: [&str; 4] = ["standard", "mania", "catch", "taiko"];
+const RATES: [f64; 2] = [0.75, 1.5];
+
+// The stretched audio is delayed by this much, which `change_rate` compensates for.
+const AUDIO_DELAY: f64 = 75.;
+
+fn read_fixture(name: &str) -> String {
+    fs::read_to_string(Path::new("tests/fixtures").join(name).with_extension("osu")).unwrap()
+}
+
+fn parse(source: &str) -> Beatmap {
+    Beatmap::parse(source.as_bytes()).unwrap()
+}
+
+fn rated(source: &str, rate: f64) -> Beatmap {
+    let mut map = parse(source);
+    map.change_rate(rate, &RateOptions::default()).unwrap();
+    map
+}
+
+// Times are truncated when written, so this is what a time of `time` at 1.0x should be read back as.
+fn expected_time(time: f64, rate: f64) -> f64 {
+    (time / rate + AUDIO_DELAY) as i32 as f64
+}
+
+#[test]
+fn rated_maps_match_expected_output() {
+    let bless = env::var_os("OSURATE_BLESS").is_some();
+    for name in &FIXTURES {
+        for &rate in &RATES {
+            let output = rated(&read_fixture(name), rate).into_string();
+            let expected_path = Path::new("tests/fixtures/expected").join(format!("{}_{}.osu", name, rate));
+            if bless {
+                fs::write(&expected_path, &output).unwrap();
+            }
+            let expected = fs::read_to_string(&expected_path).unwrap().replace("\r\n", "\n");
+            assert_eq!(output, expected, "{} at {}x doesn't match {}", name, rate, expected_path.display());
+        }
+    }
+}
+
+#[test]
+fn timing_points_are_rescaled() {
+    for name in &FIXTURES {
+        for &rate in &RATES {
+            let original = parse(&read_fixture(name));
+            let changed = parse(&rated(&read_fixture(name), rate).into_string());
+            assert_eq!(original.timing_points.len(), changed.timing_points.len());
+
+            for (old, new) in original.timing_points.iter().zip(&changed.timing_points) {
+                assert_eq!(new.time, expected_time(old.time, rate), "{} at {}x", name, rate);
+
+                // Inherited timing points store a slider velocity multiplier instead, which shouldn't change.
+                let beat_len = if old.beat_len.is_sign_positive() { old.beat_len / rate } else { old.beat_len };
+                assert!((new.beat_len - beat_len).abs() < 1e-9, "{} at {}x", name, rate);
+            }
+        }
+    }
+}
+
+#[test]
+fn hit_objects_are_retimed() {
+    for name in &FIXTURES {
+        for &rate in &RATES {
+            let original = parse(&read_fixture(name));
+            let changed = parse(&rated(&read_fixture(name), rate).into_string());
+            assert_eq!(original.hit_objects.len(), changed.hit_objects.len());
+
+            for (old, new) in original.hit_objects.iter().zip(&changed.hit_objects) {
+                assert_eq!(new.time, expected_time(old.time, rate), "{} at {}x", name, rate);
+                match (&old.params, &new.params) {
+                    (HitObjectParams::Spinner(old_end), HitObjectParams::Spinner(new_end))
+                    | (HitObjectParams::LongNote(old_end), HitObjectParams::LongNote(new_end)) => {
+                        assert_eq!(*new_end, expected_time(*old_end, rate), "{} at {}x", name, rate);
+                    }
+                    (HitObjectParams::NoneUseful, HitObjectParams::NoneUseful) => {}
+                    _ => panic!("{} at {}x changed the type of a hit object", name, rate),
+                }
+            }
+        }
+    }
+}
+
+// Returns the lines of the [HitObjects] section of `source`.
+fn hit_object_lines(source: &str) -> Vec<String> {
+    let lines = source.lines().skip_while(|&l| l.trim_end() != "[HitObjects]").skip(1);
+    lines.map(|l| l.trim_end().to_string()).filter(|l| !l.is_empty()).collect()
+}
+
+#[test]
+fn hit_object_types_and_hit_sounds_are_kept() {
+    // The new combo and combo colour bits of the type, and the hit sounds (i.e. don/kat in taiko), shouldn't change.
+    for name in &FIXTURES {
+        let source = read_fixture(name);
+        let output = rated(&source, 1.5).into_string();
+        for (old, new) in hit_object_lines(&source).iter().zip(hit_object_lines(&output)) {
+            let (old, new) = (old.split(',').collect::<Vec<_>>(), new.split(',').collect::<Vec<_>>());
+            assert_eq!((&old[..2], &old[3..5]), (&new[..2], &new[3..5]), "{}", name);
+        }
+    }
+}
+
+#[test]
+fn rating_at_one_keeps_the_timing() {
+    for name in &FIXTURES {
+        let source = read_fixture(name);
+        let original = parse(&source);
+        let changed = parse(&rated(&source, 1.).into_string());
+        assert!(changed.metadata.diff_name.ends_with(" (1x)"));
+
+        let times = |map: &Beatmap| map.hit_objects.iter().map(|o| o.time).collect::<Vec<_>>();
+        assert_eq!(times(&original), times(&changed), "{}", name);
+    }
+}